@@ -1,42 +1,775 @@
-use std::{convert::TryInto, io, path::Path};
-
-use anyhow::Result;
-use exchange::{Exchange, Transaction};
-use log::{debug, warn};
-
-use crate::conversion::RawTransaction;
-
-pub fn run<P: AsRef<Path>, W: io::Write>(input: P, writer: W) -> Result<()> {
-    let mut reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .flexible(true)
-        .quoting(false)
-        .delimiter(b',')
-        .double_quote(false)
-        .has_headers(true)
-        .comment(Some(b'#'))
-        .from_path(input.as_ref())?;
-
-    let mut exchange = Exchange::new();
-
-    for result in reader.deserialize() {
-        let raw: RawTransaction = if let Ok(raw) = result { raw } else { continue };
-
-        let transaction: Transaction = match raw.try_into() {
-            Err(e) => {
-                debug!("Invalid transaction {}", e);
-                continue;
+use std::{collections::HashMap, io, str::FromStr};
+
+use anyhow::{anyhow, Error, Result};
+use exchange::{
+    check_headers, csv_reader_builder, ClientID, ErrorKind, Exchange, ExchangeConfig,
+    ExchangeError, PrecisionPolicy, RawTransaction, RoundingMode, Transaction,
+};
+use log::{debug, info, warn};
+use serde::Serialize;
+
+/// A row of the input that was rejected, either because it could not be
+/// parsed or because the exchange refused the transaction
+#[derive(Debug, Serialize)]
+struct Rejection {
+    /// Line of the row in the input
+    line: u64,
+    /// Index of the row among the records of the input, starting at 1 for the
+    /// first record after the header
+    #[serde(skip)]
+    record: u64,
+    /// The row as it appeared in the input
+    row: String,
+    /// Why the row was rejected
+    reason: String,
+    /// The rule of the exchange that rejected the row, if any
+    #[serde(skip)]
+    kind: Option<ErrorKind>,
+}
+
+/// Output format for the final client states
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Csv,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "json" => Ok(Format::Json),
+            _ => Err(anyhow!("Unknown output format {}", s)),
+        }
+    }
+}
+
+/// Summary of a run
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RunStats {
+    /// Number of transactions accepted by the exchange
+    pub processed: u64,
+    /// Number of rows that could not be parsed into a transaction
+    pub parse_failures: u64,
+    /// Number of transactions rejected by the exchange
+    pub handle_failures: u64,
+    /// Number of disputes, resolves and chargebacks rejected because the
+    /// referenced transaction doesn't exist. They are included in
+    /// `handle_failures`; a spike may point to transactions arriving out of
+    /// order upstream.
+    pub unknown_transactions: u64,
+}
+
+impl RunStats {
+    /// Total number of rejected rows
+    #[must_use]
+    pub const fn rejected(&self) -> u64 {
+        self.parse_failures + self.handle_failures
+    }
+}
+
+/// Default number of decimal places of the amounts in the output
+pub const DEFAULT_PRECISION: u32 = 4;
+
+/// Options controlling how the input is processed and the output is written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Options {
+    /// Output format for the final client states
+    pub format: Format,
+    /// Number of decimal places of the amounts in the output
+    pub precision: u32,
+    /// Buffer the input and handle the transactions in order of their
+    /// timestamps instead of the order they appear in. Rows without a
+    /// timestamp keep their position in the input.
+    pub sort_by_time: bool,
+    /// Abort on the first rejected row instead of logging it and carrying on
+    pub strict: bool,
+    /// Lines of the input starting with this character are ignored
+    pub comment: Option<u8>,
+    /// Accept rows with a varying number of fields
+    pub flexible: bool,
+    /// Treat double quotes as quoting fields, e.g. `"1.5"`
+    pub quoting: bool,
+    /// How to handle amounts with more than four places past the decimal
+    pub amount_precision: PrecisionPolicy,
+    /// How to round amounts in the output
+    pub rounding: RoundingMode,
+    /// Only write the final states of these clients, e.g. to debug a single
+    /// client. All transactions are still processed.
+    pub clients: Option<Vec<ClientID>>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            format: Format::default(),
+            precision: DEFAULT_PRECISION,
+            sort_by_time: false,
+            strict: false,
+            comment: Some(b'#'),
+            flexible: true,
+            quoting: false,
+            amount_precision: PrecisionPolicy::Reject,
+            rounding: RoundingMode::HalfEven,
+            clients: None,
+        }
+    }
+}
+
+/// Parse a row of the input into a transaction
+fn parse_record(
+    record: csv::Result<csv::StringRecord>,
+    headers: &csv::StringRecord,
+    policy: PrecisionPolicy,
+) -> Result<(Transaction, Rejection), Rejection> {
+    let record = record.map_err(|e| {
+        let record = e.position().map_or(0, csv::Position::record);
+        debug!("Invalid row in record {}: {}", record, e);
+        Rejection {
+            line: e.position().map_or(0, csv::Position::line),
+            record,
+            row: String::new(),
+            reason: e.to_string(),
+            kind: None,
+        }
+    })?;
+    // Kept around in case the exchange rejects the transaction
+    let rejection = Rejection {
+        line: record.position().map_or(0, csv::Position::line),
+        record: record.position().map_or(0, csv::Position::record),
+        row: record.iter().collect::<Vec<_>>().join(","),
+        reason: String::new(),
+        kind: None,
+    };
+
+    match record
+        .deserialize::<RawTransaction>(Some(headers))
+        .map_err(Error::from)
+        .and_then(|raw| Ok(raw.into_transaction(policy)?))
+    {
+        Err(e) => {
+            debug!("Invalid transaction in record {}: {}", rejection.record, e);
+            Err(Rejection {
+                reason: e.to_string(),
+                kind: e.downcast_ref::<ExchangeError>().map(ExchangeError::kind),
+                ..rejection
+            })
+        }
+        Ok(transaction) => Ok((transaction, rejection)),
+    }
+}
+
+/// Count the rejected rows by the rule that rejected them. Rows that could not
+/// be read at all are counted under `None`.
+fn count_by_kind(rejections: &[Rejection]) -> HashMap<Option<ErrorKind>, usize> {
+    let mut counts = HashMap::new();
+    for rejection in rejections {
+        *counts.entry(rejection.kind).or_insert(0) += 1;
+    }
+    counts
+}
+
+/// Apply the transactions read from `input` to `exchange`, collecting the
+/// rejected rows in `rejections`
+fn process<R: io::Read>(
+    input: R,
+    exchange: &mut Exchange,
+    options: &Options,
+    rejections: &mut Vec<Rejection>,
+    stats: &mut RunStats,
+) -> Result<()> {
+    let mut reader = csv_reader_builder()
+        .comment(options.comment)
+        .flexible(options.flexible)
+        .quoting(options.quoting)
+        .double_quote(options.quoting)
+        .from_reader(input);
+    let headers = reader.headers()?.clone();
+    check_headers(&headers)?;
+
+    let rows = reader
+        .records()
+        .map(|record| parse_record(record, &headers, options.amount_precision));
+    let rows: Box<dyn Iterator<Item = _>> = if options.sort_by_time {
+        let timestamp = |row: &Option<Result<(Transaction, Rejection), Rejection>>| {
+            row.as_ref()?.as_ref().ok()?.0.timestamp
+        };
+        let mut rows: Vec<_> = rows.map(Some).collect();
+        // Only the rows with a timestamp are reordered, among the positions
+        // they take. Rows without one, including unreadable rows, stay where
+        // they are. The sort is stable, so rows with equal timestamps keep
+        // their order.
+        let slots: Vec<usize> = (0..rows.len())
+            .filter(|&index| timestamp(&rows[index]).is_some())
+            .collect();
+        let mut timed: Vec<_> = slots.iter().map(|&index| rows[index].take()).collect();
+        timed.sort_by_key(timestamp);
+        for (index, row) in slots.into_iter().zip(timed) {
+            rows[index] = row;
+        }
+        Box::new(rows.into_iter().flatten())
+    } else {
+        Box::new(rows)
+    };
+
+    for row in rows {
+        let rejection = match row {
+            Err(rejection) => {
+                stats.parse_failures += 1;
+                rejection
             }
-            Ok(t) => t,
+            Ok((transaction, rejection)) => match exchange.handle(&transaction) {
+                Ok(_) => {
+                    stats.processed += 1;
+                    continue;
+                }
+                Err(e) => {
+                    stats.handle_failures += 1;
+                    if e.kind() == ErrorKind::UnknownTransaction {
+                        stats.unknown_transactions += 1;
+                    }
+                    warn!("Transaction in record {} failed: {}", rejection.record, e);
+                    Rejection {
+                        reason: e.to_string(),
+                        kind: Some(e.kind()),
+                        ..rejection
+                    }
+                }
+            },
         };
-        if let Err(e) = exchange.handle(transaction) {
-            warn!("Transaction failed: {}", e);
+        if options.strict {
+            return Err(anyhow!(
+                "Rejected record {} on line {}: {}",
+                rejection.record,
+                rejection.line,
+                rejection.reason
+            ));
         }
+        rejections.push(rejection);
     }
+    Ok(())
+}
 
-    let mut writer = csv::Writer::from_writer(writer);
-    for client in exchange.clients() {
-        writer.serialize(client)?;
+/// Apply the transactions read from `inputs` in order and write the final
+/// client states to `writer`. If `rejected` is given, all rejected rows get
+/// written to it together with the reason of the rejection. Returns how many
+/// rows were accepted and rejected.
+///
+/// All inputs are applied to the same exchange, e.g. files split by month,
+/// and the combined client states are written once at the end. Every input
+/// must start with its own header row, and `Options::sort_by_time` sorts the
+/// transactions within each input.
+pub fn run<R: io::Read, W: io::Write>(
+    inputs: impl IntoIterator<Item = R>,
+    writer: W,
+    options: &Options,
+    rejected: Option<&mut dyn io::Write>,
+) -> Result<RunStats> {
+    let mut exchange =
+        Exchange::with_config(ExchangeConfig::default().with_rounding(options.rounding));
+    let mut rejections = Vec::new();
+    let mut stats = RunStats::default();
+    for input in inputs {
+        process(input, &mut exchange, options, &mut rejections, &mut stats)?;
+    }
+
+    for (kind, count) in count_by_kind(&rejections) {
+        match kind {
+            Some(kind) => info!("Rejected {} rows: {:?}", count, kind),
+            None => info!("Rejected {} malformed rows", count),
+        }
+    }
+
+    if let Some(rejected) = rejected {
+        let mut writer = csv::Writer::from_writer(rejected);
+        for rejection in rejections {
+            writer.serialize(rejection)?;
+        }
+    }
+
+    // Sorted by client ID, so the output is deterministic
+    let mut clients = exchange.snapshot();
+    if let Some(filter) = &options.clients {
+        clients.retain(|client| filter.contains(&client.id));
+        for id in filter {
+            if exchange.get_client(*id).is_none() {
+                warn!("Client {} does not exist", id);
+            }
+        }
+    }
+    let clients: Vec<_> = clients
+        .iter()
+        .map(|client| {
+            client
+                .rounded(options.precision)
+                .with_rounding(exchange.config().rounding)
+        })
+        .collect();
+
+    match options.format {
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for client in clients {
+                writer.serialize(client)?;
+            }
+        }
+        Format::Json => serde_json::to_writer(writer, &clients)?,
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+
+    const INPUT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../fixtures/example/input.csv");
+
+    /// Run the CLI on `input` and return the output
+    fn output<R: io::Read>(input: R, format: Format, precision: u32) -> String {
+        let options = Options {
+            format,
+            precision,
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+        run([input], &mut output, &options, None).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_reader_matches_file() {
+        let from_file = output(File::open(INPUT).unwrap(), Format::Csv, DEFAULT_PRECISION);
+        let csv = fs::read_to_string(INPUT).unwrap();
+        let from_reader = output(csv.as_bytes(), Format::Csv, DEFAULT_PRECISION);
+
+        assert!(!from_file.is_empty());
+        assert_eq!(from_file, from_reader);
+    }
+
+    #[test]
+    fn test_gzip_matches_plain() {
+        use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+        use std::io::Write;
+
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,10.0\n\
+                   withdrawal,1,2,2.5\n\
+                   deposit,2,3,1.0\n";
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(csv.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let plain = output(csv.as_bytes(), Format::Csv, DEFAULT_PRECISION);
+        let gzipped = output(
+            GzDecoder::new(compressed.as_slice()),
+            Format::Csv,
+            DEFAULT_PRECISION,
+        );
+        assert!(!plain.is_empty());
+        assert_eq!(plain, gzipped);
+    }
+
+    #[test]
+    fn test_output_is_deterministic() {
+        let csv = fs::read_to_string(INPUT).unwrap();
+        assert_eq!(
+            output(csv.as_bytes(), Format::Csv, DEFAULT_PRECISION),
+            output(csv.as_bytes(), Format::Csv, DEFAULT_PRECISION)
+        );
+    }
+
+    #[test]
+    fn test_output_sorted_by_client() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,3,1,1.0\n\
+                   deposit,1,2,1.0\n\
+                   deposit,2,3,1.0\n";
+        let clients: Vec<String> = output(csv.as_bytes(), Format::Csv, DEFAULT_PRECISION)
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().to_string())
+            .collect();
+        assert_eq!(clients, vec!["1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_formats() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.2346\n\
+                   deposit,2,2,2.0\n\
+                   withdrawal,2,3,0.5\n";
+
+        assert_eq!(
+            output(csv.as_bytes(), Format::Csv, DEFAULT_PRECISION),
+            "client,available,held,total,locked\n\
+             1,1.2346,0.0000,1.2346,false\n\
+             2,1.5,0.0000,1.5,false\n"
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&output(csv.as_bytes(), Format::Json, DEFAULT_PRECISION)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"client": 1, "available": "1.2346", "held": "0.0000", "total": "1.2346", "locked": false},
+                {"client": 2, "available": "1.5", "held": "0.0000", "total": "1.5", "locked": false},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!("csv".parse::<Format>().unwrap(), Format::Csv);
+        assert_eq!("json".parse::<Format>().unwrap(), Format::Json);
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn test_precision() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.2346\n";
+        let output = |precision| output(csv.as_bytes(), Format::Csv, precision);
+        assert!(output(2).ends_with("1,1.23,0.00,1.23,false\n"));
+        assert!(output(4).ends_with("1,1.2346,0.0000,1.2346,false\n"));
+        assert!(output(10).ends_with("1,1.2346,0.0000000000,1.2346,false\n"));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   deposit,1,2,abc\n\
+                   withdrawal,1,3,10.0\n";
+        let mut output = Vec::new();
+        let mut rejected = Vec::new();
+        run(
+            [csv.as_bytes()],
+            &mut output,
+            &Options::default(),
+            Some(&mut rejected),
+        )
+        .unwrap();
+
+        let rejected = String::from_utf8(rejected).unwrap();
+        let lines: Vec<&str> = rejected.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "line,row,reason");
+        assert!(lines[1].starts_with("3,\"deposit,1,2,abc\","));
+        assert!(lines[1].contains("expected a Decimal"));
+        assert!(lines[2].starts_with("4,\"withdrawal,1,3,10.0\","));
+        assert!(lines[2].contains("Insufficient funds"));
+    }
+
+    #[test]
+    fn test_sort_by_time() {
+        let csv = "type,client,tx,amount,timestamp\n\
+                   dispute,1,1,,2021-05-02T12:00:00Z\n\
+                   deposit,1,1,5.0,2021-05-01T12:00:00Z\n";
+        let output = |sort_by_time| {
+            let options = Options {
+                sort_by_time,
+                ..Options::default()
+            };
+            let mut output = Vec::new();
+            let mut rejected = Vec::new();
+            run([csv.as_bytes()], &mut output, &options, Some(&mut rejected)).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            (output, String::from_utf8(rejected).unwrap().lines().count())
+        };
+
+        // The dispute references a deposit that wasn't handled yet
+        let (unsorted, rejected) = output(false);
+        assert!(unsorted.ends_with("1,5,0.0000,5,false\n"));
+        assert_eq!(rejected, 2);
+
+        let (sorted, rejected) = output(true);
+        assert!(sorted.ends_with("1,0.0000,5,5,false\n"));
+        assert_eq!(rejected, 0);
+    }
+
+    #[test]
+    fn test_sort_by_time_mixed() {
+        let csv = "type,client,tx,amount,timestamp\n\
+                   deposit,1,2,1.0,2021-05-03T12:00:00Z\n\
+                   deposit,1,1,5.0,2021-05-01T12:00:00Z\n\
+                   dispute,1,1,,\n\
+                   deposit,1,3,abc,\n\
+                   withdrawal,1,4,6.0,2021-05-02T12:00:00Z\n";
+        let options = Options {
+            sort_by_time: true,
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+        let mut rejected = Vec::new();
+        let stats = run([csv.as_bytes()], &mut output, &options, Some(&mut rejected)).unwrap();
+
+        // The timestamped rows take their positions in the order tx 1, 4, 2.
+        // The rows without a timestamp stay in place, so the dispute still
+        // comes after the deposit it references. The withdrawal comes before
+        // the deposit of tx 2, which leaves too little for it.
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1,5,6,false\n"
+        );
+        assert_eq!(stats.parse_failures, 1);
+        assert_eq!(stats.handle_failures, 1);
+        let rejected = String::from_utf8(rejected).unwrap();
+        let records: Vec<&str> = rejected.lines().skip(1).collect();
+        assert_eq!(records.len(), 2);
+        assert!(records[0].contains("withdrawal"));
+        assert!(records[1].contains("abc"));
+    }
+
+    #[test]
+    fn test_strict() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   withdrawal,1,2,10.0\n\
+                   deposit,1,3,abc\n\
+                   deposit,2,4,1.0\n";
+        let options = Options {
+            strict: true,
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+        let err = run([csv.as_bytes()], &mut output, &options, None).unwrap_err();
+        let err = err.to_string();
+        assert!(err.starts_with("Rejected record 2 on line 3:"));
+        assert!(err.contains("Insufficient funds"));
+        assert!(output.is_empty());
+
+        // Lenient mode carries on after the bad rows
+        let mut rejected = Vec::new();
+        run(
+            [csv.as_bytes()],
+            &mut output,
+            &Options::default(),
+            Some(&mut rejected),
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(rejected).unwrap().lines().count(), 3);
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("2,1,0.0000,1,false\n"));
+    }
+
+    #[test]
+    fn test_error_reports_record() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   deposit,1,2,abc\n\
+                   deposit,1,3,1.0\n";
+        let options = Options {
+            strict: true,
+            ..Options::default()
+        };
+        let err = run([csv.as_bytes()], Vec::new(), &options, None).unwrap_err();
+        let err = err.to_string();
+        assert!(err.starts_with("Rejected record 2 on line 3:"));
+        assert!(err.contains("expected a Decimal"));
+    }
+
+    #[test]
+    fn test_reader_options() {
+        let csv = "type,client,tx,amount\n\
+                   ; comment\n\
+                   deposit,1,1,\"1.5\"\n\
+                   dispute,1,1\n";
+        let run = |options: &Options| {
+            let mut output = Vec::new();
+            let mut rejected = Vec::new();
+            run([csv.as_bytes()], &mut output, options, Some(&mut rejected)).unwrap();
+            let rejected = String::from_utf8(rejected).unwrap();
+            // Skip the header
+            (
+                String::from_utf8(output).unwrap(),
+                rejected.lines().skip(1).count(),
+            )
+        };
+
+        // Quotes are part of the value by default. The rejected dispute
+        // doesn't create the client, so there is no output.
+        let (output, rejected) = run(&Options::default());
+        assert_eq!(output, "");
+        assert_eq!(rejected, 3);
+
+        let options = Options {
+            comment: Some(b';'),
+            quoting: true,
+            ..Options::default()
+        };
+        let (output, rejected) = run(&options);
+        assert!(output.ends_with("1,0.0000,1.5,1.5,false\n"));
+        assert_eq!(rejected, 0);
+
+        // The dispute lacks the amount column
+        let (output, rejected) = run(&Options {
+            flexible: false,
+            ..options
+        });
+        assert!(output.ends_with("1,1.5,0.0000,1.5,false\n"));
+        assert_eq!(rejected, 1);
+    }
+
+    #[test]
+    fn test_count_by_kind() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   deposit,1,1,5.0\n\
+                   withdrawal,1,2,10.0\n\
+                   withdrawal,1,3,10.0\n\
+                   refund,1,4,1.0\n\
+                   deposit,1,5,abc\n";
+        let mut reader = csv_reader_builder().from_reader(csv.as_bytes());
+        let headers = reader.headers().unwrap().clone();
+        let mut exchange = Exchange::new();
+        let rejections: Vec<Rejection> = reader
+            .records()
+            .filter_map(
+                |record| match parse_record(record, &headers, PrecisionPolicy::Reject) {
+                    Err(rejection) => Some(rejection),
+                    Ok((transaction, rejection)) => {
+                        exchange.handle(&transaction).err().map(|e| Rejection {
+                            kind: Some(e.kind()),
+                            ..rejection
+                        })
+                    }
+                },
+            )
+            .collect();
+
+        let counts = count_by_kind(&rejections);
+        assert_eq!(counts.len(), 4);
+        assert_eq!(counts[&Some(ErrorKind::DuplicateId)], 1);
+        assert_eq!(counts[&Some(ErrorKind::InsufficientFunds)], 2);
+        assert_eq!(counts[&Some(ErrorKind::UnsupportedType)], 1);
+        assert_eq!(counts[&None], 1);
+    }
+
+    #[test]
+    fn test_stats() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   deposit,1,2,abc\n\
+                   withdrawal,1,3,10.0\n\
+                   withdrawal,1,4,1.0\n";
+        let stats = run([csv.as_bytes()], io::sink(), &Options::default(), None).unwrap();
+        assert_eq!(
+            stats,
+            RunStats {
+                processed: 2,
+                parse_failures: 1,
+                handle_failures: 1,
+                unknown_transactions: 0,
+            }
+        );
+        assert_eq!(stats.rejected(), 2);
+    }
+
+    #[test]
+    fn test_wrong_header() {
+        let csv = "kind,client_id,id,amount\n\
+                   deposit,1,1,5.0\n";
+        let mut output = Vec::new();
+        let err = run([csv.as_bytes()], &mut output, &Options::default(), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid header row: missing columns: type, client, tx; unexpected columns: kind, client_id, id"
+        );
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn test_amount_precision() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,1.23456\n";
+        let output = |amount_precision| {
+            let options = Options {
+                amount_precision,
+                ..Options::default()
+            };
+            let mut output = Vec::new();
+            let stats = run([csv.as_bytes()], &mut output, &options, None).unwrap();
+            (String::from_utf8(output).unwrap(), stats.parse_failures)
+        };
+        assert_eq!(output(PrecisionPolicy::Reject), (String::new(), 1));
+        let (truncated, failures) = output(PrecisionPolicy::Truncate);
+        assert!(truncated.ends_with("1,1.2345,0.0000,1.2345,false\n"));
+        assert_eq!(failures, 0);
+    }
+
+    #[test]
+    fn test_multiple_inputs() {
+        let january = "type,client,tx,amount\n\
+                       deposit,1,1,10.0\n\
+                       deposit,2,2,3.0\n";
+        let february = "type,client,tx,amount\n\
+                        withdrawal,1,3,4.0\n\
+                        dispute,2,2,\n\
+                        deposit,1,1,1.0\n";
+        let mut output = Vec::new();
+        let stats = run(
+            [january.as_bytes(), february.as_bytes()],
+            &mut output,
+            &Options::default(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n\
+             1,6,0.0000,6,false\n\
+             2,0.0000,3,3,false\n"
+        );
+        // Transaction IDs are unique across all inputs
+        assert_eq!(stats.processed, 4);
+        assert_eq!(stats.handle_failures, 1);
+    }
+
+    #[test]
+    fn test_unknown_transactions() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   dispute,1,2,\n\
+                   resolve,1,3,\n\
+                   chargeback,1,4,\n\
+                   dispute,1,1,\n\
+                   withdrawal,1,5,10.0\n";
+        let stats = run([csv.as_bytes()], io::sink(), &Options::default(), None).unwrap();
+        assert_eq!(stats.unknown_transactions, 3);
+        assert_eq!(stats.handle_failures, 4);
+        assert_eq!(stats.processed, 2);
+    }
+
+    #[test]
+    fn test_client_filter() {
+        let csv = "type,client,tx,amount\n\
+                   deposit,1,1,5.0\n\
+                   deposit,2,2,3.0\n\
+                   deposit,3,3,1.0\n\
+                   withdrawal,2,4,1.0\n";
+        let output = |clients| {
+            let options = Options {
+                clients: Some(clients),
+                ..Options::default()
+            };
+            let mut output = Vec::new();
+            let stats = run([csv.as_bytes()], &mut output, &options, None).unwrap();
+            assert_eq!(stats.processed, 4);
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(
+            output(vec![2]),
+            "client,available,held,total,locked\n2,2,0.0000,2,false\n"
+        );
+        assert_eq!(
+            output(vec![3, 1]),
+            "client,available,held,total,locked\n1,5,0.0000,5,false\n3,1,0.0000,1,false\n"
+        );
+        assert_eq!(output(vec![42]), "");
     }
-    Ok(())
 }
@@ -1,8 +1,14 @@
 //! # Exchange CLI
 //!
-//! `exchange-cli` is a parser for files containing exchange transactions.  At
-//! the moment only CSV files are accepted.  Invalid transactions get logged but
-//! otherwise ignored as per the specification.
+//! `exchange-cli` is a parser for files containing exchange transactions.  CSV
+//! files are accepted, optionally gzip-compressed.  If no file or `-` is given,
+//! CSV is read from stdin.  Several files are processed in order into the same
+//! exchange.  Invalid transactions get logged and skipped as per the
+//! specification, but the exit code is 3 if any row was rejected.
+//!
+//! With `--ndjson`, newline-delimited JSON transactions are streamed from a
+//! single input instead, and the updated client state is printed after every
+//! accepted transaction.
 //!
 //! The exchange-cli binary is just a wrapper around the [`exchange`] library. It
 //! provides convenience functions for interacting with an exchange from from
@@ -20,27 +26,178 @@
 )]
 
 mod cli;
-mod conversion;
+mod ndjson;
 
 use anyhow::Result;
+use cli::Options;
+use exchange::{PrecisionPolicy, RoundingMode};
+use flate2::read::GzDecoder;
 use log::{error, warn};
 use std::env;
+use std::fs::File;
 use std::io;
 
 const EXIT_NO_FILE: i32 = 1;
 const EXIT_INVALID: i32 = 2;
+const EXIT_REJECTED: i32 = 3;
+
+fn usage() -> ! {
+    error!("Usage: cargo run -- [OPTIONS] transactions.csv... > accounts.csv");
+    error!("       cargo run -- [OPTIONS] - < transactions.csv");
+    error!("       cargo run -- --ndjson [--gzip] [transactions.ndjson] > accounts.ndjson");
+    error!("Options: --format csv|json  Output format (default: csv)");
+    error!("         --precision N      Decimal places of amounts (default: 4)");
+    error!("         --rounding MODE    Rounding of amounts: half-even, half-up or down (default: half-even)");
+    error!("         --rejected FILE    Write rejected rows and reasons to FILE");
+    error!("         --client IDS       Only print the clients in the comma-separated list IDS");
+    error!("         --sort-by-time     Handle transactions in order of their timestamps; rows without one keep their position");
+    error!("         --gzip             Input is gzip-compressed (implied by a .gz extension)");
+    error!("         --ndjson           Stream NDJSON from a single input, only --gzip applies");
+    error!("         --strict           Abort on the first rejected row or missing file");
+    error!(
+        "         --comment-char C   Ignore lines starting with C, empty to disable (default: #)"
+    );
+    error!("         --no-flexible      Reject rows with a varying number of fields");
+    error!("         --quoting          Treat double quotes as quoting fields");
+    error!("         --truncate-amounts Truncate amounts to four decimal places instead of rejecting them");
+    std::process::exit(EXIT_NO_FILE);
+}
+
+/// Open an input file, or stdin for `-`
+fn open(path: &str, gzip: bool) -> io::Result<Box<dyn io::Read>> {
+    let reader: Box<dyn io::Read> = if path == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    Ok(if gzip || path.ends_with(".gz") {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        reader
+    })
+}
 
 fn main() -> Result<()> {
     env_logger::init();
 
-    let path = env::args().nth(1).unwrap_or_else(|| {
-        error!("Usage: cargo run -- transactions.csv > accounts.csv");
-        std::process::exit(EXIT_NO_FILE);
-    });
+    let mut options = Options::default();
+    let mut rejected = None;
+    let mut inputs = Vec::new();
+    let mut gzip = false;
+    let mut ndjson = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--format" {
+            options.format = args
+                .next()
+                .and_then(|format| format.parse().ok())
+                .unwrap_or_else(|| usage());
+        } else if arg == "--rejected" {
+            let path = args.next().unwrap_or_else(|| usage());
+            rejected = Some(File::create(&path).unwrap_or_else(|err| {
+                error!("Cannot create {}: {}", path, err);
+                usage()
+            }));
+        } else if arg == "--precision" {
+            options.precision = args
+                .next()
+                .and_then(|precision| precision.parse().ok())
+                .unwrap_or_else(|| usage());
+        } else if arg == "--rounding" {
+            options.rounding = match args.next().as_deref() {
+                Some("half-even") => RoundingMode::HalfEven,
+                Some("half-up") => RoundingMode::HalfUp,
+                Some("down") => RoundingMode::ToZero,
+                _ => usage(),
+            };
+        } else if arg == "--client" {
+            let clients = args.next().unwrap_or_else(|| usage());
+            options.clients = Some(
+                clients
+                    .split(',')
+                    .map(|id| id.trim().parse())
+                    .collect::<Result<_, _>>()
+                    .unwrap_or_else(|_| usage()),
+            );
+        } else if arg == "--sort-by-time" {
+            options.sort_by_time = true;
+        } else if arg == "--comment-char" {
+            let comment = args.next().unwrap_or_else(|| usage());
+            options.comment = match comment.as_bytes() {
+                [] => None,
+                [comment] => Some(*comment),
+                _ => usage(),
+            };
+        } else if arg == "--no-flexible" {
+            options.flexible = false;
+        } else if arg == "--truncate-amounts" {
+            options.amount_precision = PrecisionPolicy::Truncate;
+        } else if arg == "--quoting" {
+            options.quoting = true;
+        } else if arg == "--strict" {
+            options.strict = true;
+        } else if arg == "--gzip" {
+            gzip = true;
+        } else if arg == "--ndjson" {
+            ndjson = true;
+        } else {
+            inputs.push(arg);
+        }
+    }
 
-    if let Err(err) = cli::run(path, io::stdout()) {
-        error!("Cannot handle input file: {:?}", err);
-        std::process::exit(EXIT_INVALID);
+    let rejected = rejected.as_mut().map(|file| -> &mut dyn io::Write { file });
+    let result = if ndjson {
+        // The stream is handled line by line, none of the CSV options apply
+        if inputs.len() > 1 || rejected.is_some() || options != Options::default() {
+            usage();
+        }
+        let path = inputs.first().map_or("-", String::as_str);
+        let reader = open(path, gzip).unwrap_or_else(|err| {
+            error!("Cannot open {}: {}", path, err);
+            std::process::exit(EXIT_NO_FILE);
+        });
+        ndjson::run(io::BufReader::new(reader), io::stdout()).map(|()| None)
+    } else {
+        if inputs.is_empty() {
+            inputs.push("-".to_string());
+        }
+        let mut readers = Vec::new();
+        for path in &inputs {
+            match open(path, gzip) {
+                Ok(reader) => readers.push(reader),
+                Err(err) if options.strict => {
+                    error!("Cannot open {}: {}", path, err);
+                    std::process::exit(EXIT_NO_FILE);
+                }
+                Err(err) => error!("Cannot open {}, skipping it: {}", path, err),
+            }
+        }
+        if readers.is_empty() {
+            usage();
+        }
+        cli::run(readers, io::stdout(), &options, rejected).map(Some)
+    };
+    match result {
+        Err(err) => {
+            error!("Cannot handle input file: {:?}", err);
+            std::process::exit(EXIT_INVALID);
+        }
+        Ok(Some(stats)) => {
+            eprintln!(
+                "Processed {} transactions, rejected {} malformed rows and {} transactions",
+                stats.processed, stats.parse_failures, stats.handle_failures
+            );
+            if stats.unknown_transactions > 0 {
+                warn!(
+                    "{} disputes, resolves or chargebacks referenced unknown transactions",
+                    stats.unknown_transactions
+                );
+            }
+            if stats.rejected() > 0 {
+                std::process::exit(EXIT_REJECTED);
+            }
+        }
+        Ok(None) => {}
     }
 
     Ok(())
@@ -0,0 +1,81 @@
+use std::{
+    convert::TryInto,
+    io::{self, BufRead},
+};
+
+use anyhow::Result;
+use exchange::{Exchange, RawTransaction, Transaction};
+use log::{debug, warn};
+
+/// Process a stream of newline-delimited JSON transactions.
+///
+/// Every line is handled as soon as it was read, which allows piping
+/// transactions into the exchange continuously. For every accepted transaction
+/// the updated client state is written to `writer` as a JSON line. Malformed
+/// lines and invalid transactions get logged but otherwise ignored.
+pub fn run<R: BufRead, W: io::Write>(reader: R, mut writer: W) -> Result<()> {
+    let mut exchange = Exchange::new();
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let raw: RawTransaction = match serde_json::from_str(&line) {
+            Err(e) => {
+                debug!("Malformed line {}: {}", line, e);
+                continue;
+            }
+            Ok(raw) => raw,
+        };
+        let transaction: Transaction = match raw.try_into() {
+            Err(e) => {
+                debug!("Invalid transaction {}", e);
+                continue;
+            }
+            Ok(t) => t,
+        };
+        let client = match exchange.handle(&transaction) {
+            Err(e) => {
+                warn!("Transaction failed: {}", e);
+                continue;
+            }
+            Ok(client) => client,
+        };
+
+        serde_json::to_writer(&mut writer, &client)?;
+        writeln!(writer)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ndjson_stream() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}
+{"type": "deposit", "client": 2, "tx": 2, "amount": "1.0"}
+{"type": "deposit", "client": 1,
+{"type": "withdrawal", "client": 1, "tx": 3, "amount": "1.25"}
+
+{"type": "withdrawal", "client": 2, "tx": 4, "amount": "5.0"}
+"#;
+        let mut output = Vec::new();
+        run(input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"client":1,"available":"2.5","held":"0.0000","total":"2.5","locked":false}"#,
+                r#"{"client":2,"available":"1.0","held":"0.0000","total":"1.0","locked":false}"#,
+                r#"{"client":1,"available":"1.25","held":"0.0000","total":"1.25","locked":false}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! End-to-end test of the CLI binary against a fixed input and its expected
+//! output
+
+use std::{fs, path::PathBuf, process::Command};
+
+const INPUT: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+deposit,3,4,7.0
+dispute,1,1,
+resolve,1,1,
+dispute,2,2,
+chargeback,2,2,
+dispute,3,4,
+deposit,1,5,abc
+withdrawal,3,6,1.0
+";
+
+/// Expected output, sorted by client
+const OUTPUT: &str = "client,available,held,total,locked
+1,7.5,0.0000,7.5,false
+2,0.0000,0.0000,0.0000,true
+3,0.0000,7,7,false
+";
+
+/// Write `contents` to a file in the temporary directory, named after the test
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("exchange-cli-{}-{}", std::process::id(), name));
+    fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn test_golden_output() {
+    let input = temp_file("golden.csv", INPUT);
+    let output = Command::new(env!("CARGO_BIN_EXE_exchange-cli"))
+        .arg(&input)
+        .output()
+        .unwrap();
+    fs::remove_file(&input).unwrap();
+
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), OUTPUT);
+    // The malformed row and the withdrawal of held funds get rejected
+    assert_eq!(output.status.code(), Some(3));
+    let summary = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        summary.contains("Processed 9 transactions, rejected 1 malformed rows and 1 transactions")
+    );
+}
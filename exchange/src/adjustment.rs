@@ -0,0 +1,18 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::{Balance, ClientID};
+
+/// An administrative credit or debit of a client outside the regular
+/// transaction flow, e.g. a goodwill credit. See `Exchange::apply_adjustment`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Adjustment<C = ClientID> {
+    /// The adjusted client
+    pub client: C,
+    /// Change of the available and total funds, negative for a debit
+    #[cfg_attr(feature = "serde", serde(with = "crate::amount::exact"))]
+    pub delta: Balance,
+    /// Why the adjustment was made
+    pub note: String,
+}
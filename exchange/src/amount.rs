@@ -1,25 +1,402 @@
-use rust_decimal::Decimal;
+use std::{
+    convert::TryFrom,
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    str::FromStr,
+};
 
-/// Monetary amounts are represented as decimals interally.
+use rust_decimal::{Decimal, RoundingStrategy};
+#[cfg(feature = "serde")]
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{client::PRECISION, ExchangeError};
+
+/// Balances of clients are represented as decimals interally.
 /// This prevents rounding and conversion issues.
 /// We use `rust_decimal` as it integrates well with `serde`
 /// and is a well-tested crate
-pub type Amount = Decimal;
+///
+/// Unlike an `Amount`, a balance can become negative, e.g. if a deposit gets
+/// disputed after its funds were withdrawn.
+pub type Balance = Decimal;
+
+/// The amount of a transaction.
+///
+/// Amounts are never negative and have at most `SCALE` places past the
+/// decimal. Both are checked on construction, so any `Amount` is valid.
+///
+/// The exchange books amounts with four places, which is the default scale.
+/// Other scales allow using the same type for other products, e.g.
+/// `Amount<2>` for fiat currencies or `Amount<8>` for crypto currencies.
+/// Amounts of different scales are different types, so they cannot be mixed
+/// up.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount<const SCALE: u32 = PRECISION>(Decimal);
+
+impl<const SCALE: u32> Amount<SCALE> {
+    /// An amount of zero
+    pub const ZERO: Self = Amount(Decimal::ZERO);
+    /// The largest representable amount
+    pub const MAX: Self = Amount(Decimal::MAX);
+    /// The maximum number of places past the decimal
+    pub const SCALE: u32 = SCALE;
+
+    /// Create an amount from a mantissa and a scale, e.g. `Amount::new(12_345,
+    /// 4)` is `1.2345`. Use `Amount::try_new` for values from untrusted input.
+    ///
+    /// ## Panics
+    ///
+    /// Panics if the amount is negative or has more than `SCALE` places past
+    /// the decimal
+    #[must_use]
+    pub fn new(num: i64, scale: u32) -> Self {
+        match Self::try_new(Decimal::new(num, scale)) {
+            Ok(amount) => amount,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Create an amount from a decimal value
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::InvalidAmount` if the value is negative or has
+    /// more than `SCALE` places past the decimal
+    pub fn try_new(value: Decimal) -> Result<Self, ExchangeError> {
+        if value.is_sign_negative() && !value.is_zero() {
+            return Err(ExchangeError::InvalidAmount(
+                value.to_string(),
+                "Amount must not be negative".to_string(),
+            ));
+        }
+        if value.round_dp(SCALE) != value {
+            return Err(ExchangeError::InvalidAmount(
+                value.to_string(),
+                format!("Amount has more than {SCALE} places past the decimal"),
+            ));
+        }
+        Ok(Amount(value))
+    }
+
+    /// Create an amount from a decimal value from input, handling values with
+    /// more than `SCALE` places past the decimal according to `policy`
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::InvalidAmount` if the value is negative, or
+    /// has too many places past the decimal and `policy` is
+    /// `PrecisionPolicy::Reject`
+    pub fn with_precision(value: Decimal, policy: PrecisionPolicy) -> Result<Self, ExchangeError> {
+        match policy {
+            // Negative values must not be truncated to zero
+            PrecisionPolicy::Truncate if value.is_sign_positive() => {
+                Self::try_new(value.round_dp_with_strategy(SCALE, RoundingStrategy::ToZero))
+            }
+            _ => Self::try_new(value),
+        }
+    }
+
+    /// The decimal value of the amount
+    #[must_use]
+    pub const fn value(self) -> Decimal {
+        self.0
+    }
+
+    /// Whether the amount is zero
+    #[must_use]
+    pub const fn is_zero(self) -> bool {
+        self.0.is_zero()
+    }
+
+    /// Add two amounts, returning `None` on overflow
+    #[must_use]
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    /// Subtract an amount, returning `None` if the result would be negative
+    #[must_use]
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        if other > self {
+            return None;
+        }
+        self.0.checked_sub(other.0).map(Amount)
+    }
+}
+
+/// How to handle amounts with more than the supported places past the decimal
+/// in input
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum PrecisionPolicy {
+    /// Reject the amount
+    #[default]
+    Reject,
+    /// Drop the places past the supported ones, e.g. `0.12349` becomes
+    /// `0.1234` at the default scale
+    Truncate,
+}
+
+impl<const SCALE: u32> Add for Amount<SCALE> {
+    type Output = Self;
+
+    /// ## Panics
+    ///
+    /// Panics on overflow
+    fn add(self, other: Self) -> Self {
+        self.checked_add(other).expect("Amount overflow")
+    }
+}
+
+impl<const SCALE: u32> AddAssign for Amount<SCALE> {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl<const SCALE: u32> Sub for Amount<SCALE> {
+    type Output = Self;
+
+    /// ## Panics
+    ///
+    /// Panics if the result would be negative
+    fn sub(self, other: Self) -> Self {
+        self.checked_sub(other)
+            .expect("Amount must not be negative")
+    }
+}
+
+impl<const SCALE: u32> SubAssign for Amount<SCALE> {
+    fn sub_assign(&mut self, other: Self) {
+        *self = *self - other;
+    }
+}
+
+impl AddAssign<Amount> for Balance {
+    fn add_assign(&mut self, other: Amount) {
+        *self += other.0;
+    }
+}
+
+impl SubAssign<Amount> for Balance {
+    fn sub_assign(&mut self, other: Amount) {
+        *self -= other.0;
+    }
+}
+
+impl<const SCALE: u32> TryFrom<Decimal> for Amount<SCALE> {
+    type Error = ExchangeError;
+
+    fn try_from(value: Decimal) -> Result<Self, Self::Error> {
+        Self::try_new(value)
+    }
+}
+
+impl<const SCALE: u32> From<Amount<SCALE>> for Decimal {
+    fn from(amount: Amount<SCALE>) -> Self {
+        amount.0
+    }
+}
+
+impl PartialEq<Amount> for Decimal {
+    fn eq(&self, other: &Amount) -> bool {
+        *self == other.0
+    }
+}
+
+impl PartialEq<Decimal> for Amount {
+    fn eq(&self, other: &Decimal) -> bool {
+        self.0 == *other
+    }
+}
+
+impl<const SCALE: u32> FromStr for Amount<SCALE> {
+    type Err = ExchangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = Decimal::from_str(s)
+            .map_err(|err| ExchangeError::InvalidAmount(s.to_string(), err.to_string()))?;
+        Self::try_new(value)
+    }
+}
+
+impl<const SCALE: u32> fmt::Display for Amount<SCALE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<const SCALE: u32> Serialize for Amount<SCALE> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        exact::serialize(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, const SCALE: u32> Deserialize<'de> for Amount<SCALE> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Self::try_new(exact::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+/// Decimals are written as strings in human-readable formats like CSV and
+/// JSON. Binary formats like bincode can't read them back, as `rust_decimal`
+/// deserializes them from any type, so they get the exact 16-byte
+/// representation of the decimal there instead.
+#[cfg(feature = "serde")]
+pub(crate) mod exact {
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[allow(clippy::trivially_copy_pass_by_ref)]
+    pub(crate) fn serialize<S: Serializer>(
+        value: &Decimal,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            Serialize::serialize(value, serializer)
+        } else {
+            value.serialize().serialize(serializer)
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Decimal, D::Error> {
+        if deserializer.is_human_readable() {
+            <Decimal as Deserialize>::deserialize(deserializer)
+        } else {
+            <[u8; 16]>::deserialize(deserializer).map(Decimal::deserialize)
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::str::FromStr;
+
+    // Most tests use the default scale of the exchange
+    type Amount = super::Amount;
 
     #[test]
     fn test_amount_normal() {
         let amount = Amount::from_str("1.2345").unwrap();
-        assert_eq!(format!("{}", amount), "1.2345");
+        assert_eq!(format!("{amount}"), "1.2345");
+    }
+
+    #[test]
+    fn test_balance_truncated() {
+        let balance = Balance::from_str("1.23456789").unwrap();
+        assert_eq!(format!("{}", balance.round_dp(4)), "1.2346");
+    }
+
+    #[test]
+    fn test_amount_construction() {
+        assert_eq!(Amount::try_new(Decimal::new(5, 1)), Ok(Amount::new(5, 1)));
+        assert_eq!(Amount::try_from(Decimal::ZERO), Ok(Amount::ZERO));
+        // Trailing zeros don't count as places past the decimal
+        assert!(Amount::from_str("1.23450").is_ok());
+        assert_eq!(Amount::new(12, 1).value(), Decimal::new(12, 1));
+    }
+
+    #[test]
+    fn test_amount_rejects_invalid() {
+        assert!(matches!(
+            Amount::try_new(Decimal::new(-1, 0)),
+            Err(ExchangeError::InvalidAmount(..))
+        ));
+        assert!(matches!(
+            Amount::from_str("0.12345"),
+            Err(ExchangeError::InvalidAmount(..))
+        ));
+        assert!(matches!(
+            Amount::from_str("abc"),
+            Err(ExchangeError::InvalidAmount(..))
+        ));
     }
 
     #[test]
-    fn test_amount_truncated() {
-        let amount = Amount::from_str("1.23456789").unwrap();
-        assert_eq!(format!("{}", amount.round_dp(4)), "1.2346");
+    #[should_panic(expected = "negative")]
+    fn test_amount_new_negative() {
+        let _ = Amount::new(-1, 0);
+    }
+
+    #[test]
+    fn test_amount_arithmetic() {
+        let mut amount = Amount::new(15, 1) + Amount::new(25, 1);
+        assert_eq!(amount, Amount::new(4, 0));
+        amount -= Amount::new(1, 0);
+        assert_eq!(amount, Amount::new(3, 0));
+        amount += Amount::new(5, 4);
+        assert_eq!(amount - Amount::new(3, 0), Amount::new(5, 4));
+        assert_eq!(Amount::new(1, 0).checked_sub(Amount::new(2, 0)), None);
+        assert_eq!(Amount::MAX.checked_add(Amount::new(1, 0)), None);
+
+        let mut balance = Balance::new(1, 0);
+        balance -= Amount::new(3, 0);
+        balance += Amount::new(5, 1);
+        assert_eq!(balance, Balance::new(-15, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "negative")]
+    fn test_amount_sub_negative() {
+        let _ = Amount::new(1, 0) - Amount::new(2, 0);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_amount_serde() {
+        let amount: Amount = serde_json::from_str("\"1.5\"").unwrap();
+        assert_eq!(amount, Amount::new(15, 1));
+        assert_eq!(serde_json::to_string(&amount).unwrap(), "\"1.5\"");
+        assert!(serde_json::from_str::<Amount>("\"-1.5\"").is_err());
+        assert!(serde_json::from_str::<Amount>("\"1.23456\"").is_err());
+    }
+
+    #[test]
+    fn test_precision_policy() {
+        let value = Decimal::from_str("0.12349").unwrap();
+        assert!(matches!(
+            Amount::with_precision(value, PrecisionPolicy::Reject),
+            Err(ExchangeError::InvalidAmount(..))
+        ));
+        assert_eq!(
+            Amount::with_precision(value, PrecisionPolicy::Truncate),
+            Ok(Amount::new(1234, 4))
+        );
+        assert!(Amount::with_precision(Decimal::new(-1, 5), PrecisionPolicy::Truncate).is_err());
+    }
+
+    #[test]
+    fn test_amount_scale() {
+        assert_eq!(Amount::SCALE, 4);
+        assert!(Amount::from_str("1.2345").is_ok());
+        assert!(Amount::from_str("1.23456789").is_err());
+
+        assert_eq!(super::Amount::<8>::SCALE, 8);
+        let crypto = super::Amount::<8>::from_str("1.23456789").unwrap();
+        assert_eq!(crypto.value(), Decimal::new(123_456_789, 8));
+        assert!(super::Amount::<8>::from_str("1.234567891").is_err());
+        assert_eq!(
+            super::Amount::<8>::with_precision(
+                Decimal::new(1_234_567_891, 9),
+                PrecisionPolicy::Truncate
+            ),
+            Ok(crypto)
+        );
+
+        assert!(super::Amount::<2>::from_str("1.23").is_ok());
+        assert!(super::Amount::<2>::from_str("1.234").is_err());
+        assert!(super::Amount::<2>::try_new(Decimal::new(1234, 3))
+            .unwrap_err()
+            .to_string()
+            .contains("more than 2 places"));
     }
 }
@@ -0,0 +1,198 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    hash::Hash,
+};
+
+use crate::{Balance, Client, ClientID, Exchange, ExchangeConfig, ExchangeError, Transaction};
+
+/// Identifies an asset traded on a `MultiAssetExchange`, e.g. a currency code
+pub trait AssetId: Copy + Eq + Hash + fmt::Debug {}
+
+impl<A: Copy + Eq + Hash + fmt::Debug> AssetId for A {}
+
+/// The funds of a client in a single asset
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct AssetBalance {
+    /// Funds available for trading, withdrawals, etc.
+    pub available: Balance,
+    /// Funds held for disputes
+    pub held: Balance,
+    /// Sum of the available and held funds
+    pub total: Balance,
+}
+
+impl<C> From<&Client<C>> for AssetBalance {
+    fn from(client: &Client<C>) -> Self {
+        Self {
+            available: client.available,
+            held: client.held,
+            total: client.total,
+        }
+    }
+}
+
+/// An exchange where clients hold funds in several assets.
+///
+/// Every asset is booked on a separate `Exchange`, so the balances of a client
+/// in different assets are independent, and transaction IDs are only checked
+/// for uniqueness within an asset. Locks apply to the whole account though: a
+/// chargeback in one asset locks the client in all assets.
+#[derive(Debug)]
+pub struct MultiAssetExchange<A> {
+    config: ExchangeConfig,
+    assets: HashMap<A, Exchange>,
+    locked: HashSet<ClientID>,
+}
+
+impl<A: AssetId> MultiAssetExchange<A> {
+    /// Create a new, empty exchange
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_config(ExchangeConfig::default())
+    }
+
+    /// Create a new, empty exchange, which books every asset with the given
+    /// configuration
+    #[must_use]
+    pub fn with_config(config: ExchangeConfig) -> Self {
+        Self {
+            config,
+            assets: HashMap::new(),
+            locked: HashSet::new(),
+        }
+    }
+
+    /// Handle a transaction in the given asset, see `Exchange::handle`.
+    /// Returns the balance of the client in that asset afterwards.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::Locked` if the account of the client is locked,
+    /// or the error of the exchange of the asset
+    pub fn handle(
+        &mut self,
+        asset: A,
+        transaction: &Transaction,
+    ) -> Result<AssetBalance, ExchangeError> {
+        let config = self.config;
+        let exchange = self
+            .assets
+            .entry(asset)
+            .or_insert_with(|| Exchange::with_config(config));
+        if self.locked.contains(&transaction.client) {
+            let mut client = exchange
+                .get_client(transaction.client)
+                .copied()
+                .unwrap_or_else(|| Client::new(transaction.client));
+            client.locked = true;
+            return Err(ExchangeError::Locked(client));
+        }
+        let client = exchange.handle(transaction)?;
+        if client.locked {
+            self.locked.insert(client.id);
+        }
+        Ok(AssetBalance::from(&client))
+    }
+
+    /// The balance of a client in an asset, if the client has handled any
+    /// transactions in it
+    #[must_use]
+    pub fn balance(&self, client: ClientID, asset: A) -> Option<AssetBalance> {
+        self.assets
+            .get(&asset)?
+            .get_client(client)
+            .map(AssetBalance::from)
+    }
+
+    /// The balances of a client in all of its assets
+    #[must_use]
+    pub fn balances(&self, client: ClientID) -> HashMap<A, AssetBalance> {
+        self.assets
+            .iter()
+            .filter_map(|(asset, exchange)| {
+                exchange
+                    .get_client(client)
+                    .map(|client| (*asset, AssetBalance::from(client)))
+            })
+            .collect()
+    }
+
+    /// Whether the account of a client is locked, e.g. after a chargeback in
+    /// any asset
+    #[must_use]
+    pub fn is_locked(&self, client: ClientID) -> bool {
+        self.locked.contains(&client)
+    }
+}
+
+impl<A: AssetId> Default for MultiAssetExchange<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, ErrorKind, TransactionType};
+
+    fn deposit(tx: u32, amount: i64) -> Transaction {
+        Transaction::new(tx, 1, TransactionType::Deposit(Amount::new(amount, 0)))
+    }
+
+    fn withdraw(tx: u32, amount: i64) -> Transaction {
+        Transaction::new(tx, 1, TransactionType::Withdraw(Amount::new(amount, 0)))
+    }
+
+    #[test]
+    fn test_independent_balances() {
+        let mut exchange = MultiAssetExchange::new();
+        assert!(exchange.handle("BTC", &deposit(1, 2)).is_ok());
+        assert!(exchange.handle("USD", &deposit(2, 100)).is_ok());
+
+        let balances = exchange.balances(1);
+        assert_eq!(balances.len(), 2);
+        assert_eq!(balances["BTC"].total, Amount::new(2, 0));
+        assert_eq!(balances["USD"].total, Amount::new(100, 0));
+        assert_eq!(exchange.balance(1, "EUR"), None);
+    }
+
+    #[test]
+    fn test_withdraw_per_asset() {
+        let mut exchange = MultiAssetExchange::new();
+        assert!(exchange.handle("BTC", &deposit(1, 2)).is_ok());
+        assert!(exchange.handle("USD", &deposit(2, 100)).is_ok());
+
+        // The USD funds don't count towards the BTC funds
+        let err = exchange.handle("BTC", &withdraw(3, 50)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InsufficientFunds);
+        let balance = exchange.handle("USD", &withdraw(4, 50)).unwrap();
+        assert_eq!(balance.available, Amount::new(50, 0));
+        assert_eq!(
+            exchange.balance(1, "BTC").unwrap().available,
+            Amount::new(2, 0)
+        );
+    }
+
+    #[test]
+    fn test_lock_is_account_wide() {
+        let mut exchange = MultiAssetExchange::new();
+        assert!(exchange.handle("BTC", &deposit(1, 2)).is_ok());
+        assert!(exchange.handle("USD", &deposit(1, 100)).is_ok());
+        let dispute = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle("BTC", &dispute).is_ok());
+        let chargeback = Transaction::new(1, 1, TransactionType::Chargeback);
+        assert!(exchange.handle("BTC", &chargeback).is_ok());
+
+        assert!(exchange.is_locked(1));
+        assert!(matches!(
+            exchange.handle("USD", &withdraw(2, 1)),
+            Err(ExchangeError::Locked(_))
+        ));
+        assert_eq!(
+            exchange.balance(1, "USD").unwrap().total,
+            Amount::new(100, 0)
+        );
+    }
+}
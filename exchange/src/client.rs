@@ -1,6 +1,11 @@
-use serde::{Serialize, Serializer};
+use std::fmt;
 
-use crate::Amount;
+#[cfg(feature = "serde")]
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+
+#[cfg(feature = "serde")]
+use crate::RoundingMode;
+use crate::{Amount, Balance, ExchangeError, Id, NegativeBalancePolicy};
 
 /// Precision of output fractional
 pub(crate) const PRECISION: u32 = 4;
@@ -9,7 +14,8 @@ pub(crate) const PRECISION: u32 = 4;
 /// Make client ID a separate type to allow for future upgrades
 pub type ClientID = u16;
 
-fn serialize_amount<S>(amount: &Amount, serializer: S) -> Result<S::Ok, S::Error>
+#[cfg(feature = "serde")]
+fn serialize_amount<S>(amount: &Balance, serializer: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
 {
@@ -17,33 +23,416 @@ where
 }
 
 /// Encapsulates the state of a single client
-#[derive(Debug, Copy, Clone, Serialize, PartialEq)]
-pub struct Client {
+///
+/// When serialized, amounts are rounded to `PRECISION` places. Use
+/// [`Client::rounded`] to serialize with a different precision.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Client<C = ClientID> {
     /// Unique ID
-    #[serde(rename(serialize = "client"))]
-    pub id: ClientID,
+    #[cfg_attr(feature = "serde", serde(rename = "client"))]
+    pub id: C,
     /// Amount available for transactions (i.e. not locked by disputes)
-    #[serde(serialize_with = "serialize_amount")]
-    pub available: Amount,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_amount"))]
+    pub available: Balance,
     /// The total funds that are available or held. This should be equal to available + held
-    #[serde(serialize_with = "serialize_amount")]
-    pub held: Amount,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_amount"))]
+    pub held: Balance,
     /// Total amount in account
-    #[serde(serialize_with = "serialize_amount")]
-    pub total: Amount,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_amount"))]
+    pub total: Balance,
     /// Whether the account is locked. An account is locked if a charge back occurs
     pub locked: bool,
+    /// Number of accepted deposits. Not part of the serialized client, see
+    /// `RoundedClient::with_activity`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub deposit_count: u32,
+    /// Number of accepted withdrawals
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub withdraw_count: u32,
+    /// Sum of all accepted deposits
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub deposit_volume: Amount,
+    /// Sum of all accepted withdrawals, not including fees
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub withdraw_volume: Amount,
 }
 
-impl Client {
+impl<C: Id> Client<C> {
     /// Create a new client with the given ID
-    pub fn new(id: ClientID) -> Self {
+    #[must_use]
+    pub fn new(id: C) -> Self {
         Self {
             id,
-            available: Amount::default(),
-            held: Amount::default(),
-            total: Amount::default(),
+            available: Balance::default(),
+            held: Balance::default(),
+            total: Balance::default(),
+            locked: false,
+            deposit_count: 0,
+            withdraw_count: 0,
+            deposit_volume: Amount::ZERO,
+            withdraw_volume: Amount::ZERO,
+        }
+    }
+
+    /// Serializable view of the client, with amounts rounded to `precision`
+    /// decimal places instead of the default of four. Midpoints are rounded to
+    /// the nearest even number, see `RoundedClient::with_rounding` to change
+    /// that.
+    #[cfg(feature = "serde")]
+    #[must_use]
+    pub const fn rounded(&self, precision: u32) -> RoundedClient<'_, C> {
+        RoundedClient {
+            client: self,
+            precision,
+            rounding: RoundingMode::HalfEven,
+            activity: false,
+            status: false,
+        }
+    }
+
+    /// The funds that can be taken out of the account: the available funds
+    /// less `reserve`, e.g. `ClientLimits::min_balance`. Zero if the client
+    /// is locked or has less available than the reserve. Withdrawal fees are
+    /// not accounted for, see `Exchange::withdrawable`.
+    #[must_use]
+    pub fn withdrawable(&self, reserve: Amount) -> Amount {
+        if self.locked {
+            return Amount::ZERO;
+        }
+        self.available
+            .checked_sub(reserve.value())
+            .and_then(|free| Amount::try_new(free).ok())
+            .unwrap_or(Amount::ZERO)
+    }
+
+    /// Check that the balances of the client are consistent:
+    /// `total` must equal `available + held` and `held` may not be negative.
+    /// `available` may only be negative under `NegativeBalancePolicy::Allow`,
+    /// where disputes of withdrawn deposits leave it negative.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::InvariantViolation` naming the violated rule
+    pub fn verify<T: Id>(
+        &self,
+        negative_balance: NegativeBalancePolicy,
+    ) -> Result<(), ExchangeError<C, T>> {
+        if self.available < Balance::ZERO && negative_balance != NegativeBalancePolicy::Allow {
+            return Err(ExchangeError::InvariantViolation(
+                *self,
+                "Available funds are negative".to_string(),
+            ));
+        }
+        if self.held < Balance::ZERO {
+            return Err(ExchangeError::InvariantViolation(
+                *self,
+                "Held funds are negative".to_string(),
+            ));
+        }
+        self.verify_total()
+    }
+
+    /// Check that `total` equals `available + held`
+    pub(crate) fn verify_total<T: Id>(&self) -> Result<(), ExchangeError<C, T>> {
+        if self.available.checked_add(self.held) != Some(self.total) {
+            return Err(ExchangeError::InvariantViolation(
+                *self,
+                "Total funds are not the sum of available and held funds".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl<C> Client<C> {
+    /// Textual status of the account: `frozen` if it is locked, `disputed`
+    /// if any funds are held and `active` otherwise
+    #[must_use]
+    pub const fn status(&self) -> &'static str {
+        if self.locked {
+            "frozen"
+        } else if self.held.is_zero() {
+            "active"
+        } else {
+            "disputed"
+        }
+    }
+}
+
+/// Human-readable summary of the client for logs, with amounts rounded to
+/// `PRECISION` places, e.g.
+/// `client 7: available=100.0000 held=0.0000 total=100.0000 locked=false`
+impl<C: fmt::Display> fmt::Display for Client<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let places = PRECISION as usize;
+        write!(
+            f,
+            "client {}: available={:.places$} held={:.places$} total={:.places$} locked={}",
+            self.id,
+            self.available.round_dp(PRECISION),
+            self.held.round_dp(PRECISION),
+            self.total.round_dp(PRECISION),
+            self.locked
+        )
+    }
+}
+
+/// A client serialized with a custom rounding precision, see
+/// [`Client::rounded`]
+#[cfg(feature = "serde")]
+#[derive(Debug, Copy, Clone)]
+pub struct RoundedClient<'a, C = ClientID> {
+    client: &'a Client<C>,
+    precision: u32,
+    rounding: RoundingMode,
+    activity: bool,
+    status: bool,
+}
+
+#[cfg(feature = "serde")]
+impl<C> RoundedClient<'_, C> {
+    /// Round the amounts with the given mode, e.g. `ExchangeConfig::rounding`
+    #[must_use]
+    pub const fn with_rounding(mut self, rounding: RoundingMode) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Include the deposit and withdrawal counters of the client, e.g. for
+    /// activity reports
+    #[must_use]
+    pub const fn with_activity(mut self, activity: bool) -> Self {
+        self.activity = activity;
+        self
+    }
+
+    /// Append the derived `status` of the client (see `Client::status`) and
+    /// `held_ratio`, the share of the total funds that is held, e.g. for risk
+    /// dashboards. The ratio is zero if the client has no funds.
+    #[must_use]
+    pub const fn with_status(mut self, status: bool) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<C: Serialize> Serialize for RoundedClient<'_, C> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let round = |amount: Balance| self.rounding.round(amount, self.precision).to_string();
+        let fields = 5 + if self.activity { 4 } else { 0 } + if self.status { 2 } else { 0 };
+        let mut state = serializer.serialize_struct("Client", fields)?;
+        state.serialize_field("client", &self.client.id)?;
+        state.serialize_field("available", &round(self.client.available))?;
+        state.serialize_field("held", &round(self.client.held))?;
+        state.serialize_field("total", &round(self.client.total))?;
+        state.serialize_field("locked", &self.client.locked)?;
+        if self.activity {
+            state.serialize_field("deposit_count", &self.client.deposit_count)?;
+            state.serialize_field("withdraw_count", &self.client.withdraw_count)?;
+            state.serialize_field("deposit_volume", &round(self.client.deposit_volume.value()))?;
+            state.serialize_field(
+                "withdraw_volume",
+                &round(self.client.withdraw_volume.value()),
+            )?;
+        }
+        if self.status {
+            let ratio = self
+                .client
+                .held
+                .checked_div(self.client.total)
+                .unwrap_or(Balance::ZERO);
+            state.serialize_field("status", self.client.status())?;
+            state.serialize_field("held_ratio", &round(ratio))?;
+        }
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_new_client() {
+        assert_eq!(
+            Client::new(1).verify(NegativeBalancePolicy::Reject),
+            Ok::<_, ExchangeError>(())
+        );
+    }
+
+    #[test]
+    fn test_verify_negative_available() {
+        let client = Client {
+            available: Balance::new(-5, 0),
+            held: Balance::new(10, 0),
+            total: Balance::new(5, 0),
+            ..Client::new(1)
+        };
+        assert_eq!(
+            client.verify(NegativeBalancePolicy::Allow),
+            Ok::<_, ExchangeError>(())
+        );
+        assert!(matches!(
+            client.verify::<crate::TransactionID>(NegativeBalancePolicy::Reject),
+            Err(ExchangeError::InvariantViolation(..))
+        ));
+    }
+
+    #[test]
+    fn test_display() {
+        let client = Client {
+            available: Balance::new(1_000_000_005, 7),
+            held: Balance::new(-5, 1),
+            total: Balance::new(995_000_005, 7),
+            locked: true,
+            ..Client::new(7)
+        };
+        assert_eq!(
+            client.to_string(),
+            "client 7: available=100.0000 held=-0.5000 total=99.5000 locked=true"
+        );
+        assert_eq!(
+            Client::new(1).to_string(),
+            "client 1: available=0.0000 held=0.0000 total=0.0000 locked=false"
+        );
+    }
+
+    #[test]
+    fn test_withdrawable() {
+        let client = Client {
+            available: Balance::new(100, 0),
+            held: Balance::new(20, 0),
+            total: Balance::new(120, 0),
+            ..Client::new(1)
+        };
+        assert_eq!(client.withdrawable(Amount::ZERO), Amount::new(100, 0));
+        assert_eq!(client.withdrawable(Amount::new(30, 0)), Amount::new(70, 0));
+        assert_eq!(client.withdrawable(Amount::new(150, 0)), Amount::ZERO);
+
+        let negative = Client {
+            available: Balance::new(-5, 0),
+            total: Balance::new(-5, 0),
+            ..Client::new(1)
+        };
+        assert_eq!(negative.withdrawable(Amount::ZERO), Amount::ZERO);
+        let locked = Client {
+            locked: true,
+            ..client
+        };
+        assert_eq!(locked.withdrawable(Amount::ZERO), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_verify_negative_held() {
+        let client = Client {
+            held: Balance::new(-1, 0),
+            total: Balance::new(-1, 0),
+            ..Client::new(1)
+        };
+        assert!(matches!(
+            client.verify::<crate::TransactionID>(NegativeBalancePolicy::Allow),
+            Err(ExchangeError::InvariantViolation(c, _)) if c == client
+        ));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_rounded() {
+        let client = Client {
+            available: Balance::new(123_456, 5),
+            total: Balance::new(123_456, 5),
+            ..Client::new(1)
+        };
+        let json = |precision| serde_json::to_value(client.rounded(precision)).unwrap();
+        assert_eq!(json(2)["available"], "1.23");
+        assert_eq!(json(4)["available"], "1.2346");
+        assert_eq!(json(4), serde_json::to_value(client).unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_rounding_mode() {
+        let available = |amount: Balance, rounding| {
+            let client = Client {
+                available: amount,
+                total: amount,
+                ..Client::new(1)
+            };
+            serde_json::to_value(client.rounded(4).with_rounding(rounding)).unwrap()["available"]
+                .clone()
+        };
+        let amount = Balance::new(123_445, 5);
+        assert_eq!(available(amount, RoundingMode::HalfEven), "1.2344");
+        assert_eq!(available(amount, RoundingMode::HalfUp), "1.2345");
+        assert_eq!(available(amount, RoundingMode::ToZero), "1.2344");
+
+        let amount = Balance::new(123_455, 5);
+        assert_eq!(available(amount, RoundingMode::HalfEven), "1.2346");
+        assert_eq!(available(amount, RoundingMode::HalfUp), "1.2346");
+        assert_eq!(available(amount, RoundingMode::ToZero), "1.2345");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_with_activity() {
+        let client = Client {
+            deposit_count: 2,
+            deposit_volume: Amount::new(15, 1),
+            ..Client::new(1)
+        };
+        let json =
+            |activity| serde_json::to_value(client.rounded(4).with_activity(activity)).unwrap();
+        assert_eq!(json(false), serde_json::to_value(client).unwrap());
+        assert!(json(false).get("deposit_count").is_none());
+        assert_eq!(json(true)["deposit_count"], 2);
+        assert_eq!(json(true)["deposit_volume"], "1.5");
+        assert_eq!(json(true)["withdraw_count"], 0);
+    }
+
+    #[cfg(feature = "csv")]
+    #[test]
+    fn test_with_status() {
+        let active = Client {
+            available: Balance::new(10, 0),
+            total: Balance::new(10, 0),
+            ..Client::new(1)
+        };
+        let frozen = Client {
+            available: Balance::new(5, 0),
+            held: Balance::new(15, 0),
+            total: Balance::new(20, 0),
+            locked: true,
+            ..Client::new(2)
+        };
+        let disputed = Client {
             locked: false,
+            ..frozen
+        };
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for client in [active, frozen, disputed, Client::new(4)] {
+            writer
+                .serialize(client.rounded(4).with_status(true))
+                .unwrap();
         }
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "client,available,held,total,locked,status,held_ratio\n\
+             1,10,0.0000,10,false,active,0.0000\n\
+             2,5,15,20,true,frozen,0.75\n\
+             2,5,15,20,false,disputed,0.75\n\
+             4,0.0000,0.0000,0.0000,false,active,0.0000\n"
+        );
+
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.serialize(active.rounded(4)).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner().unwrap()).unwrap(),
+            "client,available,held,total,locked\n1,10,0.0000,10,false\n"
+        );
     }
 }
@@ -0,0 +1,270 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use crate::{client::PRECISION, Amount, Balance};
+
+/// Tunable behavior of an exchange.
+/// The default configuration imposes no additional restrictions.
+///
+/// A configuration can be built by chaining the `with_*` methods, starting
+/// from `ExchangeConfig::default()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct ExchangeConfig {
+    /// Ceiling for the total funds held by the exchange on behalf of all
+    /// clients. Deposits that would exceed it get rejected.
+    pub max_total_liabilities: Option<Amount>,
+    /// Check that `total` equals `available + held` for every client touched
+    /// by a transaction before committing it, also in release builds. Debug
+    /// builds always check.
+    pub check_invariants: bool,
+    /// Fee charged on every withdrawal, on top of the withdrawn amount
+    pub withdrawal_fee: Option<WithdrawalFee>,
+    /// What to do when a dispute would hold more than the available funds of
+    /// a client
+    pub negative_balance: NegativeBalancePolicy,
+    /// Limits on the deposits and withdrawals of every client
+    pub limits: ClientLimits,
+    /// Withdraw all available funds if a withdrawal exceeds them, instead of
+    /// rejecting it
+    pub partial_withdrawals: bool,
+    /// Accept disputes of withdrawals. Otherwise only deposits can be
+    /// disputed.
+    pub allow_withdrawal_disputes: bool,
+    /// How to round amounts in the output, see `Client::rounded`
+    pub rounding: RoundingMode,
+    /// Number of stored transactions above which settled transactions get
+    /// evicted, see `Exchange::evict_settled`
+    pub max_transactions: Option<usize>,
+}
+
+impl Default for ExchangeConfig {
+    fn default() -> Self {
+        Self {
+            max_total_liabilities: None,
+            check_invariants: false,
+            withdrawal_fee: None,
+            negative_balance: NegativeBalancePolicy::default(),
+            limits: ClientLimits::default(),
+            partial_withdrawals: false,
+            allow_withdrawal_disputes: true,
+            rounding: RoundingMode::default(),
+            max_transactions: None,
+        }
+    }
+}
+
+impl ExchangeConfig {
+    /// Reject deposits that would raise the total funds of all clients above
+    /// `limit`
+    #[must_use]
+    pub const fn with_max_total_liabilities(mut self, limit: Amount) -> Self {
+        self.max_total_liabilities = Some(limit);
+        self
+    }
+
+    /// Check the balances of every client touched by a transaction before
+    /// committing it, also in release builds
+    #[must_use]
+    pub const fn with_check_invariants(mut self, check: bool) -> Self {
+        self.check_invariants = check;
+        self
+    }
+
+    /// Charge a fee on every withdrawal
+    #[must_use]
+    pub const fn with_withdrawal_fee(mut self, fee: WithdrawalFee) -> Self {
+        self.withdrawal_fee = Some(fee);
+        self
+    }
+
+    /// Choose what to do when a dispute would hold more than the available
+    /// funds of a client
+    #[must_use]
+    pub const fn with_negative_balance(mut self, policy: NegativeBalancePolicy) -> Self {
+        self.negative_balance = policy;
+        self
+    }
+
+    /// Limit the deposits and withdrawals of every client
+    #[must_use]
+    pub const fn with_limits(mut self, limits: ClientLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Fill withdrawals exceeding the available funds partially, instead of
+    /// rejecting them
+    #[must_use]
+    pub const fn with_partial_withdrawals(mut self, partial: bool) -> Self {
+        self.partial_withdrawals = partial;
+        self
+    }
+
+    /// Choose whether withdrawals can be disputed
+    #[must_use]
+    pub const fn with_withdrawal_disputes(mut self, allow: bool) -> Self {
+        self.allow_withdrawal_disputes = allow;
+        self
+    }
+
+    /// Choose how to round amounts in the output
+    #[must_use]
+    pub const fn with_rounding(mut self, rounding: RoundingMode) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Evict settled transactions once more than `limit` transactions are
+    /// stored
+    #[must_use]
+    pub const fn with_max_transactions(mut self, limit: usize) -> Self {
+        self.max_transactions = Some(limit);
+        self
+    }
+}
+
+/// Limits on the deposits and withdrawals of every client. Transactions
+/// exceeding them get rejected.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct ClientLimits {
+    /// Largest amount of a single deposit
+    pub max_deposit: Option<Amount>,
+    /// Largest amount of a single withdrawal, not including fees
+    pub max_withdrawal: Option<Amount>,
+    /// Largest sum of all deposits of a client
+    pub max_total_deposits: Option<Amount>,
+    /// Largest sum of all withdrawals of a client, not including fees
+    pub max_total_withdrawals: Option<Amount>,
+    /// Funds every client has to keep available. Withdrawals, including
+    /// their fees, transfers and holds can only take what exceeds it, see
+    /// `Client::withdrawable`.
+    pub min_balance: Option<Amount>,
+}
+
+/// How to round amounts to the places of the output, e.g. `1.23445` to four
+/// places
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum RoundingMode {
+    /// Round midpoints to the nearest even number (banker's rounding), e.g.
+    /// `1.2344`
+    #[default]
+    HalfEven,
+    /// Round midpoints away from zero, e.g. `1.2345`
+    HalfUp,
+    /// Drop the places past the last one, e.g. `1.2344`
+    ToZero,
+}
+
+impl RoundingMode {
+    /// Round `value` to `places` places past the decimal
+    #[must_use]
+    pub fn round(self, value: Balance, places: u32) -> Balance {
+        let strategy = match self {
+            RoundingMode::HalfEven => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::ToZero => RoundingStrategy::ToZero,
+        };
+        value.round_dp_with_strategy(places, strategy)
+    }
+}
+
+/// What to do when a dispute would make the available funds of a client
+/// negative, e.g. because the disputed deposit was already withdrawn
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NegativeBalancePolicy {
+    /// Hold the full amount, leaving the available funds negative
+    #[default]
+    Allow,
+    /// Reject the dispute with `ExchangeError::ArithmeticError`
+    Reject,
+    /// Only hold what is available, leaving the available funds at zero
+    Clamp,
+}
+
+/// Fee charged on withdrawals: a fixed amount plus a percentage of the
+/// withdrawn amount. Either part can be zero.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct WithdrawalFee {
+    /// Fixed fee per withdrawal
+    pub fixed: Amount,
+    /// Percentage of the withdrawn amount, e.g. `1.5` for 1.5%
+    pub percentage: Amount,
+}
+
+impl WithdrawalFee {
+    /// The fee for withdrawing `amount`, rounded to four places past the
+    /// decimal. Returns `None` if the fee overflows.
+    #[must_use]
+    pub fn fee_for(&self, amount: Amount) -> Option<Amount> {
+        let percentage = amount
+            .value()
+            .checked_mul(self.percentage.value())?
+            .checked_div(Decimal::new(100, 0))?
+            .round_dp(PRECISION);
+        Amount::try_new(percentage).ok()?.checked_add(self.fixed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builder() {
+        let fee = WithdrawalFee {
+            fixed: Amount::new(1, 0),
+            ..WithdrawalFee::default()
+        };
+        let limits = ClientLimits {
+            max_deposit: Some(Amount::new(10, 0)),
+            ..ClientLimits::default()
+        };
+        let config = ExchangeConfig::default()
+            .with_max_total_liabilities(Amount::new(100, 0))
+            .with_check_invariants(true)
+            .with_withdrawal_fee(fee)
+            .with_negative_balance(NegativeBalancePolicy::Clamp)
+            .with_limits(limits)
+            .with_partial_withdrawals(true)
+            .with_withdrawal_disputes(false)
+            .with_rounding(RoundingMode::HalfUp)
+            .with_max_transactions(1000);
+        assert_eq!(
+            config,
+            ExchangeConfig {
+                max_total_liabilities: Some(Amount::new(100, 0)),
+                check_invariants: true,
+                withdrawal_fee: Some(fee),
+                negative_balance: NegativeBalancePolicy::Clamp,
+                limits,
+                partial_withdrawals: true,
+                allow_withdrawal_disputes: false,
+                rounding: RoundingMode::HalfUp,
+                max_transactions: Some(1000),
+            }
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_fee() {
+        let fee = WithdrawalFee {
+            fixed: Amount::new(1, 0),
+            percentage: Amount::new(15, 1),
+        };
+        assert_eq!(fee.fee_for(Amount::new(200, 0)), Some(Amount::new(4, 0)));
+        // 1.5% of 0.0003 is rounded away
+        assert_eq!(fee.fee_for(Amount::new(3, 4)), Some(Amount::new(1, 0)));
+        assert_eq!(
+            WithdrawalFee::default().fee_for(Amount::MAX),
+            Some(Amount::ZERO)
+        );
+        assert_eq!(fee.fee_for(Amount::MAX), None);
+    }
+}
@@ -0,0 +1,294 @@
+use std::convert::TryFrom;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Amount, Balance, ClientID, ExchangeError, PrecisionPolicy, Transaction, TransactionID,
+    TransactionType,
+};
+
+/// A helper struct to read transactions from input files, e.g. CSV.
+/// The conversion to an actual `Transaction` is done in a separate step.
+/// That's because there is some verification needed to check that the transaction
+/// has an amount (in case of deposit or withdrawal) or not (otherwise)
+///
+/// Serializing a `RawTransaction` converted from a `Transaction` writes it in
+/// the input format, e.g. for exporting an audit trail that can be read again.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RawTransaction {
+    #[serde(rename = "type", alias = "ttype")]
+    ttype: String,
+    /// Read wider than `ClientID`, so that IDs out of range get a specific
+    /// error when converting into a `Transaction`
+    client: u64,
+    tx: TransactionID,
+    /// Checked when converting into a `Transaction`, see
+    /// `RawTransaction::into_transaction`
+    amount: Option<Balance>,
+    /// Destination client of a transfer
+    #[serde(default)]
+    to: Option<u64>,
+    /// When the transaction happened, in RFC 3339 format
+    #[serde(default)]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+impl TryFrom<RawTransaction> for Transaction {
+    type Error = ExchangeError;
+
+    fn try_from(raw: RawTransaction) -> Result<Self, Self::Error> {
+        raw.into_transaction(PrecisionPolicy::Reject)
+    }
+}
+
+impl RawTransaction {
+    /// Convert into a `Transaction`, handling amounts with more than four
+    /// places past the decimal according to `policy`. Converting with
+    /// `TryFrom` rejects them.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::InvalidAmount` if the amount is invalid,
+    /// `ExchangeError::ClientIdOutOfRange` if a client ID doesn't fit into
+    /// `ClientID`, `ExchangeError::UnsupportedTransaction` if the type is
+    /// unknown and `ExchangeError::MalformedTransaction` if it doesn't match
+    /// the other fields
+    pub fn into_transaction(self, policy: PrecisionPolicy) -> Result<Transaction, ExchangeError> {
+        let amount = self
+            .amount
+            .map(|amount| Amount::with_precision(amount, policy))
+            .transpose()?;
+        let to = self.to.map(client_id).transpose()?;
+        Ok(Transaction {
+            tx: self.tx,
+            client: client_id(self.client)?,
+            ttype: parse_ttype(&self.ttype, amount, to)?,
+            timestamp: self.timestamp,
+        })
+    }
+}
+
+impl From<Transaction> for RawTransaction {
+    fn from(transaction: Transaction) -> Self {
+        let (amount, to) = match transaction.ttype {
+            TransactionType::Deposit(amount)
+            | TransactionType::Withdraw(amount)
+            | TransactionType::Hold(amount)
+            | TransactionType::Release(amount) => (Some(amount), None),
+            TransactionType::Transfer { to, amount } => (Some(amount), Some(to)),
+            TransactionType::Dispute(amount)
+            | TransactionType::Resolve(amount)
+            | TransactionType::Custom(_, amount) => (amount, None),
+            TransactionType::Chargeback => (None, None),
+        };
+        RawTransaction {
+            ttype: transaction.ttype.to_string(),
+            client: transaction.client.into(),
+            tx: transaction.tx,
+            amount: amount.map(Amount::value),
+            to: to.map(u64::from),
+            timestamp: transaction.timestamp,
+        }
+    }
+}
+
+/// Helper function to narrow a client ID read from input
+fn client_id(id: u64) -> Result<ClientID, ExchangeError> {
+    ClientID::try_from(id).map_err(|_| ExchangeError::ClientIdOutOfRange(id))
+}
+
+/// Helper function to parse the transaction type
+fn parse_ttype(
+    ttype: &str,
+    amount: Option<Amount>,
+    to: Option<ClientID>,
+) -> Result<TransactionType, ExchangeError> {
+    TransactionType::from_kind(ttype.parse()?, amount, to)
+}
+
+#[cfg(all(test, feature = "csv"))]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    fn parse(input: &str) -> Vec<Result<Transaction, ExchangeError>> {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .from_reader(input.as_bytes())
+            .deserialize::<RawTransaction>()
+            .map(|raw| raw.unwrap().try_into())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_transfer() {
+        let transactions = parse("type,client,tx,amount,to\ndeposit,1,1,5.0\ntransfer,1,2,2.5,7\n");
+        assert_eq!(
+            transactions[0].as_ref().unwrap().ttype,
+            TransactionType::Deposit(Amount::new(50, 1))
+        );
+        assert_eq!(
+            transactions[1].as_ref().unwrap().ttype,
+            TransactionType::Transfer {
+                to: 7,
+                amount: Amount::new(25, 1)
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_transfer_without_destination() {
+        let transactions =
+            parse("type,client,tx,amount,to\ntransfer,1,2,2.5,\ndeposit,1,1,5.0,3\n");
+        assert!(transactions[0].is_err());
+        assert!(transactions[1].is_err());
+    }
+
+    #[test]
+    fn test_parse_invalid_amount() {
+        let transactions = parse(
+            "type,client,tx,amount\n\
+             deposit,1,1,-5.0\n\
+             deposit,1,2,0.12345\n\
+             deposit,1,3,0.1234\n",
+        );
+        assert!(matches!(
+            transactions[0],
+            Err(ExchangeError::InvalidAmount(..))
+        ));
+        assert!(matches!(
+            transactions[1],
+            Err(ExchangeError::InvalidAmount(..))
+        ));
+        assert!(transactions[2].is_ok());
+    }
+
+    #[test]
+    fn test_parse_precision_policy() {
+        let raw = || {
+            csv::Reader::from_reader("type,client,tx,amount\ndeposit,1,1,0.12345\n".as_bytes())
+                .deserialize::<RawTransaction>()
+                .next()
+                .unwrap()
+                .unwrap()
+        };
+        assert!(matches!(
+            raw().into_transaction(PrecisionPolicy::Reject),
+            Err(ExchangeError::InvalidAmount(..))
+        ));
+        assert_eq!(
+            raw()
+                .into_transaction(PrecisionPolicy::Truncate)
+                .unwrap()
+                .ttype,
+            TransactionType::Deposit(Amount::new(1234, 4))
+        );
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let transactions = parse(
+            "type,client,tx,amount,timestamp\n\
+             deposit,1,1,5.0,2021-05-01T12:00:00Z\n\
+             deposit,1,2,5.0,\n",
+        );
+        let timestamp = "2021-05-01T12:00:00Z".parse().unwrap();
+        assert_eq!(transactions[0].as_ref().unwrap().timestamp, Some(timestamp));
+        assert_eq!(transactions[1].as_ref().unwrap().timestamp, None);
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let deposit = Transaction::new(1, 2, TransactionType::Deposit(Amount::new(15, 1)));
+        let dispute = Transaction::new(1, 2, TransactionType::Dispute(None));
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for transaction in [deposit, dispute] {
+            writer.serialize(RawTransaction::from(transaction)).unwrap();
+        }
+        let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(
+            output,
+            "type,client,tx,amount,to,timestamp\n\
+             deposit,2,1,1.5,,\n\
+             dispute,2,1,,,\n"
+        );
+
+        let transactions: Vec<_> = parse(&output).into_iter().map(Result::unwrap).collect();
+        assert_eq!(transactions, vec![deposit, dispute]);
+    }
+
+    #[test]
+    fn test_parse_withdrawal_alias() {
+        let transactions = parse("type,client,tx,amount\nwithdraw,1,1,2.5\nwithdrawal,1,2,2.5\n");
+        for transaction in transactions {
+            assert_eq!(
+                transaction.unwrap().ttype,
+                TransactionType::Withdraw(Amount::new(25, 1))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_dispute_without_amount() {
+        let transactions = parse("type,client,tx,amount\ndispute,1,1,\ndispute,1,1\n");
+        for transaction in transactions {
+            assert_eq!(transaction.unwrap().ttype, TransactionType::Dispute(None));
+        }
+    }
+
+    #[test]
+    fn test_parse_client_id_out_of_range() {
+        let transactions = parse(
+            "type,client,tx,amount,to\n\
+             deposit,70000,1,5.0,\n\
+             transfer,1,2,2.5,65536\n\
+             deposit,65535,3,5.0,\n",
+        );
+        assert_eq!(
+            transactions[0],
+            Err(ExchangeError::ClientIdOutOfRange(70000))
+        );
+        assert_eq!(
+            transactions[1],
+            Err(ExchangeError::ClientIdOutOfRange(65536))
+        );
+        assert_eq!(transactions[2].as_ref().unwrap().client, 65535);
+        assert!(transactions[0]
+            .as_ref()
+            .unwrap_err()
+            .to_string()
+            .contains("Client ID `70000` is out of range"));
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let transactions = parse(
+            "type,client,tx,amount,to\n\
+             deposit,1,1,,\n\
+             chargeback,1,1,2.0,\n\
+             withdrawal,1,2,2.0,3\n\
+             transfer,1,3,2.0,\n\
+             refund,1,4,2.0,\n",
+        );
+        let messages: Vec<_> = transactions
+            .iter()
+            .map(|transaction| transaction.as_ref().unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                "Malformed transaction: deposit requires an amount",
+                "Malformed transaction: chargeback must not carry an amount",
+                "Malformed transaction: withdrawal must not carry a destination",
+                "Malformed transaction: transfer requires a destination",
+                "Unsupported transaction: type refund",
+            ]
+        );
+        assert_eq!(
+            transactions[0].as_ref().unwrap_err().kind(),
+            crate::ErrorKind::MalformedTransaction
+        );
+    }
+}
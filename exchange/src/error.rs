@@ -1,17 +1,179 @@
 use thiserror::Error;
 
-use crate::{Client, Transaction};
+use crate::{Amount, Balance, Client, ClientID, Id, Transaction, TransactionID};
+
+/// The rule that caused an error.
+/// This allows callers to handle specific error cases (e.g. for statistics)
+/// without matching on error messages.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// The amount could not be converted
+    InvalidAmount,
+    /// A client ID read from input exceeds the range of `ClientID`
+    ClientIdOutOfRange,
+    /// The transaction type is not supported by the exchange
+    UnsupportedType,
+    /// The amount or destination of a transaction doesn't match its type
+    MalformedTransaction,
+    /// The transaction ID was already used by another transaction
+    DuplicateId,
+    /// The total liabilities of the exchange would exceed the configured limit
+    LiabilityLimit,
+    /// A deposit or withdrawal exceeds the configured limits of the client
+    ClientLimit,
+    /// The client has not enough available funds
+    InsufficientFunds,
+    /// A transfer has the same source and destination client
+    SelfTransfer,
+    /// The referenced transaction does not exist
+    UnknownTransaction,
+    /// The referenced transaction is already disputed
+    AlreadyDisputed,
+    /// The referenced transaction is not disputed
+    NotDisputed,
+    /// The amount exceeds the amount held for the dispute
+    ExceedsDisputed,
+    /// The disputed amount exceeds the amount of the transaction
+    ExceedsTransaction,
+    /// The referenced transaction has no amount associated with it
+    NotAmountBearing,
+    /// The referenced transaction cannot be disputed under the configuration
+    /// of the exchange, e.g. a withdrawal
+    DisputeNotAllowed,
+    /// The referenced transaction belongs to a different client
+    ClientMismatch,
+    /// Source and destination of a transfer are on different shards
+    CrossShardTransfer,
+    /// A client has transactions in both exchanges of a merge
+    ClientConflict,
+    /// The exchange has administrative adjustments, so it cannot be
+    /// reprocessed
+    Adjusted,
+    /// A balance would overflow
+    Overflow,
+    /// A balance would become negative
+    NegativeBalance,
+    /// The client does not exist
+    UnknownClient,
+    /// The client is already registered
+    ClientExists,
+    /// The client still has funds
+    NonZeroBalance,
+    /// The client is locked
+    Locked,
+    /// The client is on the deny-list of the exchange
+    Blocked,
+    /// The balances of a client are inconsistent
+    InvariantViolation,
+    /// The header row of an input doesn't match the input format
+    InvalidHeader,
+    /// Reading or writing data failed
+    Io,
+}
 
 /// Possible errors when interacting with the exchange
 #[derive(Error, Debug, PartialEq)]
-pub enum ExchangeError {
+pub enum ExchangeError<C = ClientID, T = TransactionID> {
     /// Error during amount conversion to internal format
-    #[error("Amount conversion failed. Expected fraction with a precision of up to four places past the decimal, got `{0}`: `{1}`")]
+    #[error("Amount conversion failed. Expected a non-negative fraction with a supported precision, got `{0}`: `{1}`")]
     InvalidAmount(String, String),
+    /// A client ID read from input doesn't fit into `ClientID`
+    #[error("Client ID `{0}` is out of range. Supported client IDs are 0 to {max}", max = ClientID::MAX)]
+    ClientIdOutOfRange(u64),
+    /// The type of a transaction read from input is not supported
+    #[error("Unsupported transaction: {0}")]
+    UnsupportedTransaction(String),
+    /// The fields of a transaction read from input don't match its type, e.g.
+    /// a deposit without an amount
+    #[error("Malformed transaction: {0}")]
+    MalformedTransaction(String),
+    /// The name of a custom transaction type is invalid
+    #[error("Invalid name for a custom transaction type: `{0}`")]
+    InvalidTypeName(String),
     /// Error while validating a transaction
-    #[error("The given transaction is invalid: `{0:?}`. Transaction: `{1:?}`")]
-    InvalidTransaction(Transaction, String),
+    #[error("The given transaction is invalid: `{2}`. Transaction: `{0:?}`")]
+    InvalidTransaction(Transaction<C, T>, ErrorKind, String),
+    /// The client has not enough available funds for a withdrawal, transfer
+    /// or hold
+    #[error("Insufficient funds available for transaction of client `{client}`. Available: {available}, required: {requested}")]
+    InsufficientFunds {
+        /// The client that was short of funds
+        client: C,
+        /// The available funds of the client, less `ClientLimits::min_balance`
+        /// for withdrawals. They may be negative after a dispute.
+        available: Balance,
+        /// The amount the transaction required, including fees
+        requested: Amount,
+    },
+    /// A balance update would overflow the range of `Balance`
+    #[error("Transaction would overflow the client balance: `{0:?}`")]
+    Overflow(Transaction<C, T>),
+    /// A transaction would leave a balance of the client in an invalid state,
+    /// see `ExchangeConfig::negative_balance`
+    #[error("Transaction of client `{0}` would result in an invalid balance: {1}")]
+    ArithmeticError(C, String),
+    /// The balances of a client are inconsistent
+    #[error("Client balances are inconsistent: {1}. Client: `{0}`")]
+    InvariantViolation(Client<C>, String),
+    /// The client does not exist
+    #[error("The client `{0}` does not exist")]
+    UnknownClient(C),
+    /// The transaction does not exist
+    #[error("The transaction `{0}` does not exist")]
+    UnknownTransaction(T),
+    /// The client is already registered
+    #[error("The client `{0}` already exists")]
+    ClientExists(C),
+    /// The client still has funds, so the account cannot be closed
+    #[error("The client still has funds. `{0}`")]
+    NonZeroBalance(Client<C>),
+    /// The header row of an input doesn't match the input format
+    #[error("Invalid header row: {0}")]
+    InvalidHeader(String),
+    /// Reading or writing data failed
+    #[error("I/O error: {0}")]
+    Io(String),
     /// If a client is locked it can no longer be modified
-    #[error("The client is locked and immutable. `{0:?}`")]
-    Locked(Client),
+    #[error("The client is locked and immutable. `{0}`")]
+    Locked(Client<C>),
+    /// The client is blocked from transacting, see `Exchange::block_client`
+    #[error("The client `{0}` is blocked")]
+    Blocked(C),
+    /// Exchanges cannot be merged, as a client has transactions in both
+    #[error("The client `{0}` has transactions in both exchanges")]
+    ClientConflict(C),
+    /// The exchange cannot be reprocessed, as the client was adjusted, see
+    /// `Exchange::reprocess`
+    #[error("The client `{0}` has adjustments, which cannot be reprocessed")]
+    Adjusted(C),
+}
+
+impl<C: Id, T: Id> ExchangeError<C, T> {
+    /// The rule that caused the error
+    #[must_use]
+    pub const fn kind(&self) -> ErrorKind {
+        match self {
+            ExchangeError::InvalidAmount(..) => ErrorKind::InvalidAmount,
+            ExchangeError::ClientIdOutOfRange(_) => ErrorKind::ClientIdOutOfRange,
+            ExchangeError::InvalidTypeName(_) | ExchangeError::UnsupportedTransaction(_) => {
+                ErrorKind::UnsupportedType
+            }
+            ExchangeError::MalformedTransaction(_) => ErrorKind::MalformedTransaction,
+            ExchangeError::InvalidTransaction(_, kind, _) => *kind,
+            ExchangeError::InsufficientFunds { .. } => ErrorKind::InsufficientFunds,
+            ExchangeError::Overflow(_) => ErrorKind::Overflow,
+            ExchangeError::ArithmeticError(..) => ErrorKind::NegativeBalance,
+            ExchangeError::InvariantViolation(..) => ErrorKind::InvariantViolation,
+            ExchangeError::UnknownClient(_) => ErrorKind::UnknownClient,
+            ExchangeError::UnknownTransaction(_) => ErrorKind::UnknownTransaction,
+            ExchangeError::ClientExists(_) => ErrorKind::ClientExists,
+            ExchangeError::NonZeroBalance(_) => ErrorKind::NonZeroBalance,
+            ExchangeError::InvalidHeader(_) => ErrorKind::InvalidHeader,
+            ExchangeError::Io(_) => ErrorKind::Io,
+            ExchangeError::Locked(_) => ErrorKind::Locked,
+            ExchangeError::Blocked(_) => ErrorKind::Blocked,
+            ExchangeError::ClientConflict(_) => ErrorKind::ClientConflict,
+            ExchangeError::Adjusted(_) => ErrorKind::Adjusted,
+        }
+    }
 }
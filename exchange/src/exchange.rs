@@ -1,62 +1,884 @@
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
+    hash::BuildHasher,
+    ops::RangeBounds,
+};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "wal")]
+use crate::wal::{Wal, WalEntry};
+
+use rust_decimal::RoundingStrategy;
 
 use crate::{
-    Client, ClientID, ExchangeError, Registry, Transaction, TransactionID, TransactionType,
+    client::PRECISION, handler::Handlers, observer::ObserverSlot, Adjustment, Amount, Balance,
+    Client, ClientID, CustomType, ErrorKind, ExchangeConfig, ExchangeError, ExchangeMetrics, Id,
+    NegativeBalancePolicy, Observer, Registry, RejectionRecord, Transaction, TransactionID,
+    TransactionStore, TransactionType,
 };
 
 /// An exchange keeps track of all transactions.
 /// It is designed to always be in a valid state.
 /// If a transaction is invalid, it will be rejected by the exchanged and an error will be returned.
+///
+/// Accepted transactions are kept in a `TransactionStore`, which is a simple
+/// in-memory map ordered by transaction ID by default.
+///
+/// Client and transaction IDs are `ClientID` and `TransactionID` by default.
+/// Use `Exchange::with_store` with a store of transactions with wider IDs to
+/// support more clients or transactions.
+///
+/// With the `serde` feature, the exchange can be serialized into a
+/// self-contained snapshot and restored from it later.
 #[derive(Debug)]
-pub struct Exchange {
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(bound(
+        serialize = "S: Serialize, C: Id + Serialize, T: Id + Serialize",
+        deserialize = "S: Deserialize<'de>, C: Id + Deserialize<'de>, T: Id + Deserialize<'de>"
+    ))
+)]
+pub struct Exchange<S = BTreeMap<TransactionID, Transaction>, C = ClientID, T = TransactionID> {
+    /// Tunable behavior of the exchange
+    config: ExchangeConfig,
     /// The registry handles client lookup and registration
-    registry: Registry,
-    // We use a simple map as a datastore for accepted transations.
+    registry: Registry<C>,
+    // We use a simple ordered map as the default datastore for accepted
+    // transations, which allows iterating over ranges of transaction IDs.
     // This does not scale to a lot of transactions of course. In a real-world
     // scenario, one could use an external datastore like Redis and sharding
     // based on the client id to handle transactions on a cluster of instances.
     // (See consistent hashing) One would also have to consider disk storage
     // for backups, rollups, and migrations.
-    transactions: HashMap<TransactionID, Transaction>,
+    transactions: S,
+    /// IDs of the stored transactions of every client, in insertion order
+    history: HashMap<C, Vec<T>>,
+    /// Every accepted transaction, including disputes and their outcomes, in
+    /// the order they were accepted
+    log: Vec<Transaction<C, T>>,
+    /// Amount still held for every open dispute, keyed by the disputed
+    /// transaction
+    disputes: HashMap<T, Amount>,
+    /// Fee charged for every stored withdrawal that was charged one, so that
+    /// voiding it refunds exactly that fee
+    #[cfg_attr(feature = "serde", serde(default))]
+    fees: HashMap<T, Amount>,
+    /// Amount originally requested by every stored withdrawal that was
+    /// partially filled, to recognize retries of it
+    #[cfg_attr(feature = "serde", serde(default))]
+    requested: HashMap<T, Amount>,
+    /// IDs of the stored transactions whose dispute was resolved or charged
+    /// back, which may be evicted
+    settled: BTreeSet<T>,
+    /// IDs of the stored transactions that were charged back, which can't be
+    /// voided anymore
+    #[cfg_attr(feature = "serde", serde(default))]
+    charged_back: HashSet<T>,
+    /// Clients that may not transact, see `Exchange::block_client`
+    blocked: HashSet<C>,
+    /// Handlers for custom transaction types
+    #[cfg_attr(feature = "serde", serde(skip))]
+    handlers: Handlers<C, T>,
+    /// Sum of the total funds of all clients. It is kept up to date with every
+    /// transaction, so it doesn't have to be recomputed for every deposit.
+    /// Only tracked if a limit is configured.
+    #[cfg_attr(feature = "serde", serde(with = "crate::amount::exact"))]
+    liabilities: Balance,
+    /// Sum of all fees collected by the exchange
+    collected_fees: Amount,
+    /// Sum of all funds swept out of the reserve account, see
+    /// `Exchange::sweep_reserve`
+    swept: Amount,
+    /// Sums of the deposits and withdrawals of every client. Only tracked if a
+    /// cumulative limit is configured.
+    volumes: HashMap<C, Volume>,
+    /// Funds held by operators for every client with an administrative hold,
+    /// see `TransactionType::Hold`. Releases can't free more than these, so
+    /// funds held for disputes stay held.
+    #[cfg_attr(feature = "serde", serde(default))]
+    holds: HashMap<C, Amount>,
+    /// Administrative adjustments, in the order they were applied
+    adjustments: Vec<Adjustment<C>>,
+    /// Callback for every handled transaction, see `Exchange::set_observer`
+    #[cfg_attr(feature = "serde", serde(skip))]
+    observer: ObserverSlot<C, T>,
+    /// Log of accepted transactions, see `Exchange::set_wal`
+    #[cfg(feature = "wal")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) wal: Option<Wal<C, T>>,
 }
 
 impl Exchange {
     /// Create a new, empty exchange
+    #[must_use]
     pub fn new() -> Exchange {
+        Exchange::with_config(ExchangeConfig::default())
+    }
+
+    /// Create a new, empty exchange with the given configuration
+    #[must_use]
+    pub fn with_config(config: ExchangeConfig) -> Exchange {
+        Exchange::with_store_and_config(BTreeMap::new(), config)
+    }
+
+    /// The shard responsible for a client if the clients are split into
+    /// `shards` independent exchanges, see `ShardedExchange`
+    #[must_use]
+    pub fn shard(client: ClientID, shards: usize) -> usize {
+        usize::from(client) % shards
+    }
+}
+
+impl<C: Id, T: Id> Exchange<BTreeMap<T, Transaction<C, T>>, C, T> {
+    /// Returns an iterator over the stored transactions with an ID in the
+    /// given range, in ascending order of their IDs
+    pub fn ledger_range(
+        &self,
+        range: impl RangeBounds<T>,
+    ) -> impl Iterator<Item = &Transaction<C, T>> {
+        self.transactions
+            .range(range)
+            .map(|(_, transaction)| transaction)
+    }
+}
+
+impl<C: Id, T: Id, H: BuildHasher + Default> Exchange<HashMap<T, Transaction<C, T>, H>, C, T> {
+    /// Create a new, empty exchange for a known load, which keeps accepted
+    /// transactions in a `HashMap`.
+    ///
+    /// The registry is allocated upfront with room for `clients` clients, and
+    /// the store and the log of accepted transactions with room for
+    /// `transactions` transactions, so they don't have to grow and rehash
+    /// repeatedly while ingesting. Exceeding either capacity is fine: the maps
+    /// then grow as usual.
+    #[must_use]
+    pub fn with_capacity(clients: usize, transactions: usize) -> Self {
+        let mut exchange = Exchange::with_store(HashMap::with_capacity_and_hasher(
+            transactions,
+            H::default(),
+        ));
+        exchange.registry = Registry::with_capacity(clients);
+        exchange.history = HashMap::with_capacity(clients);
+        exchange.log = Vec::with_capacity(transactions);
+        exchange
+    }
+}
+
+impl<S: TransactionStore<C, T>, C: Id, T: Id> Exchange<S, C, T> {
+    /// Create a new, empty exchange, which keeps accepted transactions in the
+    /// given store
+    pub fn with_store(transactions: S) -> Self {
+        Exchange::with_store_and_config(transactions, ExchangeConfig::default())
+    }
+
+    /// Create a new, empty exchange with the given configuration, which keeps
+    /// accepted transactions in the given store
+    pub fn with_store_and_config(transactions: S, config: ExchangeConfig) -> Self {
         Exchange {
+            config,
             registry: Registry::new(),
-            transactions: HashMap::new(),
+            transactions,
+            history: HashMap::new(),
+            log: Vec::new(),
+            disputes: HashMap::new(),
+            fees: HashMap::new(),
+            requested: HashMap::new(),
+            settled: BTreeSet::new(),
+            charged_back: HashSet::new(),
+            blocked: HashSet::new(),
+            handlers: Handlers::default(),
+            liabilities: Balance::ZERO,
+            collected_fees: Amount::ZERO,
+            swept: Amount::ZERO,
+            volumes: HashMap::new(),
+            holds: HashMap::new(),
+            adjustments: Vec::new(),
+            observer: ObserverSlot::default(),
+            #[cfg(feature = "wal")]
+            wal: None,
         }
     }
 
+    /// Register the handler for a custom transaction type, replacing any
+    /// previously registered handler for that type. Transactions whose handler
+    /// changes the ID or lock of the client, or leaves its balances
+    /// inconsistent, are rejected with `ExchangeError::InvariantViolation`.
+    pub fn register_handler<F>(&mut self, name: CustomType, handler: F)
+    where
+        F: Fn(&mut Client<C>, Option<Amount>) -> Result<(), ExchangeError<C, T>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.handlers.insert(name, Box::new(handler));
+    }
+
+    /// The configuration of the exchange
+    #[must_use]
+    pub const fn config(&self) -> &ExchangeConfig {
+        &self.config
+    }
+
+    /// Set the callback invoked at the end of every call to `handle`, with the
+    /// transaction and its outcome, replacing any previous observer. This
+    /// allows monitoring the exchange, e.g. counting rejected transactions.
+    pub fn set_observer(&mut self, observer: Observer<C, T>) -> Option<Observer<C, T>> {
+        self.observer.set(observer)
+    }
+
     /// Returns an iterator over all active clients in the exchange registry
-    pub fn clients(&self) -> impl Iterator<Item = &Client> {
+    pub fn clients(&self) -> impl Iterator<Item = &Client<C>> {
         self.registry.clients.values()
     }
 
+    /// Returns an iterator over all clients that allows modifying them, e.g. to
+    /// apply a batch adjustment.
+    ///
+    /// Changes bypass all checks of `handle`: the caller is responsible for
+    /// keeping every client consistent (see `Exchange::verify_invariants`) and
+    /// changes to the funds are not counted towards
+    /// `ExchangeConfig::max_total_liabilities`.
+    pub fn clients_mut(&mut self) -> impl Iterator<Item = &mut Client<C>> {
+        self.registry.clients.values_mut()
+    }
+
+    /// Returns an iterator over all locked clients, e.g. after a chargeback
+    pub fn locked_clients(&self) -> impl Iterator<Item = &Client<C>> {
+        self.clients().filter(|client| client.locked)
+    }
+
+    /// A copy of the current state of all clients, sorted by client ID. Unlike
+    /// `clients`, it doesn't borrow the exchange, so it can be kept around
+    /// while more transactions are handled.
+    #[must_use]
+    pub fn snapshot(&self) -> Vec<Client<C>> {
+        let mut clients: Vec<Client<C>> = self.clients().copied().collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    /// The largest amount a client can withdraw right now: its available
+    /// funds less `ClientLimits::min_balance`, and less the fee the withdrawal
+    /// would be charged. Zero for unknown and locked clients. The other limits
+    /// of `ExchangeConfig::limits` are not taken into account.
+    #[must_use]
+    pub fn withdrawable(&self, id: C) -> Amount {
+        let Some(client) = self.get_client(id) else {
+            return Amount::ZERO;
+        };
+        let reserve = self.config.limits.min_balance.unwrap_or_default();
+        self.max_withdrawal(client.withdrawable(reserve).value())
+    }
+
+    /// Consume the exchange and return the final state of all clients, sorted
+    /// by client ID, e.g. for a final report. Unlike `snapshot`, the clients
+    /// are moved out of the registry instead of copied.
+    #[must_use]
+    pub fn into_clients(self) -> Vec<Client<C>> {
+        let mut clients: Vec<Client<C>> = self.registry.clients.into_values().collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    /// Sum of all fees collected by the exchange, e.g. for withdrawals
+    #[must_use]
+    pub const fn collected_fees(&self) -> Amount {
+        self.collected_fees
+    }
+
+    /// Funds owned by the exchange itself: the collected fees less what was
+    /// swept out with `Exchange::sweep_reserve`. Together with
+    /// `Exchange::total_liabilities`, this accounts for all funds deposited
+    /// and not withdrawn. It can become negative if a withdrawal is voided
+    /// after its fee was swept, as the fee is refunded.
+    #[must_use]
+    pub fn reserve_balance(&self) -> Balance {
+        self.collected_fees.value() - self.swept.value()
+    }
+
+    /// Take up to `amount` out of the reserve account, e.g. when an operator
+    /// moves the collected fees to the exchange's own accounts. Returns the
+    /// swept amount, which is less than `amount` if the reserve doesn't cover
+    /// it.
+    pub fn sweep_reserve(&mut self, amount: Amount) -> Amount {
+        let available =
+            Amount::try_new(self.reserve_balance().max(Balance::ZERO)).unwrap_or(Amount::ZERO);
+        let swept = amount.min(available);
+        self.swept += swept;
+        swept
+    }
+
     /// Retrieve a client from the exchange (if existing)
-    pub fn get_client(&mut self, id: ClientID) -> Option<&Client> {
+    #[must_use]
+    pub fn get_client(&self, id: C) -> Option<&Client<C>> {
         self.registry.get(&id)
     }
 
+    /// Register a client with zero balances before any transaction, e.g. when
+    /// onboarding a new account.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::ClientExists` if the client is already known
+    pub fn register_client(&mut self, id: C) -> Result<&Client<C>, ExchangeError<C, T>> {
+        match self.registry.clients.entry(id) {
+            Entry::Occupied(_) => Err(ExchangeError::ClientExists(id)),
+            Entry::Vacant(entry) => Ok(entry.insert(Client::new(id))),
+        }
+    }
+
+    /// Sum of the total funds of all clients, i.e. what the exchange owes its
+    /// clients. This is a `Balance`, as clients can have negative totals after
+    /// a chargeback.
+    #[must_use]
+    pub fn total_liabilities(&self) -> Balance {
+        self.clients().map(|client| client.total).sum()
+    }
+
+    /// Sum of the held funds of all clients, i.e. the funds under dispute
+    #[must_use]
+    pub fn total_held(&self) -> Balance {
+        self.clients().map(|client| client.held).sum()
+    }
+
+    /// Sum of the available funds of all clients
+    #[must_use]
+    pub fn total_available(&self) -> Balance {
+        self.clients().map(|client| client.available).sum()
+    }
+
+    /// The transactions that are currently disputed, in no particular order
+    pub fn open_disputes(&self) -> impl Iterator<Item = Transaction<C, T>> + '_ {
+        self.disputes
+            .keys()
+            .filter_map(move |tx| self.transactions.get(*tx))
+    }
+
+    /// Returns an iterator over all accepted transactions in the order they
+    /// were accepted, including disputes, resolves and chargebacks, e.g. for
+    /// exporting an audit log. Withdrawals appear with the amount that was
+    /// actually withdrawn, see `ExchangeConfig::partial_withdrawals`.
+    pub fn transactions_in_order(&self) -> impl Iterator<Item = &Transaction<C, T>> {
+        self.log.iter()
+    }
+
+    /// Sum of the amounts held for all open disputes, or `None` if the sum
+    /// overflows
+    #[must_use]
+    pub fn disputed_total(&self) -> Option<Amount> {
+        self.disputes
+            .values()
+            .try_fold(Amount::ZERO, |sum, held| sum.checked_add(*held))
+    }
+
+    /// A summary of the exchange for monitoring, e.g. to serve from a
+    /// `/metrics` endpoint in the Prometheus text format
+    #[must_use]
+    pub fn metrics_snapshot(&self) -> ExchangeMetrics {
+        ExchangeMetrics {
+            clients: self.client_count(),
+            locked_clients: self.locked_clients().count(),
+            transactions: self.log.len(),
+            open_disputes: self.disputes.len(),
+            total_liabilities: self.total_liabilities(),
+        }
+    }
+
+    /// Close the account of a client that has no funds left, removing it from
+    /// the exchange. Returns the final state of the client.
+    ///
+    /// The transactions of the client are kept, so their IDs cannot be
+    /// reused. If the client shows up again later, it starts with a new
+    /// account.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::UnknownClient` if the client doesn't exist,
+    /// `ExchangeError::Locked` if it is locked and
+    /// `ExchangeError::NonZeroBalance` if it has any available or held funds
+    pub fn close_account(&mut self, id: C) -> Result<Client<C>, ExchangeError<C, T>> {
+        let client = match self.registry.get(&id) {
+            Some(client) if client.locked => return Err(ExchangeError::Locked(*client)),
+            Some(client) => *client,
+            None => return Err(ExchangeError::UnknownClient(id)),
+        };
+        if !client.total.is_zero() || !client.held.is_zero() {
+            return Err(ExchangeError::NonZeroBalance(client));
+        }
+        self.registry.remove(&id);
+        Ok(client)
+    }
+
+    /// Number of clients known to the exchange
+    #[must_use]
+    pub fn client_count(&self) -> usize {
+        self.registry.clients.len()
+    }
+
+    /// Returns an iterator over the stored transactions of a client, in the
+    /// order they were accepted. Only deposits, withdrawals, transfers and
+    /// custom transactions are stored; disputes and their outcomes are not.
+    pub fn client_transactions(&self, id: C) -> impl Iterator<Item = Transaction<C, T>> + '_ {
+        self.history
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .filter_map(move |tx| self.transactions.get(*tx))
+    }
+
+    /// Lock a client, e.g. while investigating a case. A locked client can no
+    /// longer be modified by transactions.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::UnknownClient` if the client doesn't exist
+    pub fn lock_client(&mut self, id: C) -> Result<(), ExchangeError<C, T>> {
+        self.set_locked(id, true)
+    }
+
+    /// Unlock a client, e.g. after a chargeback was settled out-of-band.
+    /// The client can be modified by transactions again afterwards.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::UnknownClient` if the client doesn't exist
+    pub fn unlock_client(&mut self, id: C) -> Result<(), ExchangeError<C, T>> {
+        self.set_locked(id, false)
+    }
+
+    /// Block a client from transacting, e.g. for sanctions. Unlike locking,
+    /// this also works for clients the exchange doesn't know yet. Every
+    /// transaction of a blocked client, and every transfer to it, gets
+    /// rejected with `ExchangeError::Blocked` until it is unblocked. Returns
+    /// whether the client was not blocked before.
+    pub fn block_client(&mut self, id: C) -> bool {
+        self.blocked.insert(id)
+    }
+
+    /// Allow a blocked client to transact again. Returns whether the client
+    /// was blocked.
+    pub fn unblock_client(&mut self, id: C) -> bool {
+        self.blocked.remove(&id)
+    }
+
+    /// Whether a client is blocked, see `Exchange::block_client`
+    #[must_use]
+    pub fn is_blocked(&self, id: C) -> bool {
+        self.blocked.contains(&id)
+    }
+
+    /// Void a deposit or withdrawal that was entered by mistake, reversing its
+    /// effect on the balances of the client and removing it from the
+    /// exchange, including earlier disputes of it in
+    /// `Exchange::transactions_in_order`. Fees charged for a withdrawal are
+    /// refunded. Unlike a chargeback, voiding doesn't lock the client.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::UnknownTransaction` if the transaction doesn't
+    /// exist, `ExchangeError::Locked` if its client is locked, e.g. after a
+    /// chargeback, `ExchangeError::InvalidTransaction` if it is not a deposit
+    /// or withdrawal, is disputed or was charged back, or voiding a deposit
+    /// would make the available funds of the client negative, and
+    /// `ExchangeError::ArithmeticError` if the fee to refund exceeds the
+    /// collected fees. Returns `ExchangeError::Io` if the void cannot be
+    /// written to the write-ahead log, see `Exchange::set_wal`.
+    pub fn void_transaction(&mut self, tx: T) -> Result<(), ExchangeError<C, T>> {
+        let transaction = self
+            .transactions
+            .get(tx)
+            .ok_or(ExchangeError::UnknownTransaction(tx))?;
+        let client = self.load_client(transaction.client)?;
+        if self.disputes.contains_key(&tx) {
+            return Err(ExchangeError::InvalidTransaction(
+                transaction,
+                ErrorKind::AlreadyDisputed,
+                "Disputed transactions cannot be voided".to_string(),
+            ));
+        }
+        if self.charged_back.contains(&tx) {
+            return Err(ExchangeError::InvalidTransaction(
+                transaction,
+                ErrorKind::AlreadyDisputed,
+                "Charged back transactions cannot be voided".to_string(),
+            ));
+        }
+        let (mut client, fee) = match transaction.ttype {
+            TransactionType::Deposit(amount) => {
+                (debit(client, amount, &transaction)?, Amount::ZERO)
+            }
+            TransactionType::Withdraw(amount) => {
+                let fee = self.fees.get(&tx).copied().unwrap_or(Amount::ZERO);
+                let refund = amount
+                    .checked_add(fee)
+                    .ok_or(ExchangeError::Overflow(transaction))?;
+                (credit(client, refund, &transaction)?, fee)
+            }
+            _ => {
+                return Err(ExchangeError::InvalidTransaction(
+                    transaction,
+                    ErrorKind::UnsupportedType,
+                    "Only deposits and withdrawals can be voided".to_string(),
+                ))
+            }
+        };
+        let collected_fees = self.collected_fees.checked_sub(fee).ok_or_else(|| {
+            ExchangeError::ArithmeticError(
+                transaction.client,
+                format!(
+                    "Refunding a fee of {fee} exceeds the collected fees of {}",
+                    self.collected_fees
+                ),
+            )
+        })?;
+        let (count, volume) = match transaction.ttype {
+            TransactionType::Deposit(_) => (&mut client.deposit_count, &mut client.deposit_volume),
+            _ => (&mut client.withdraw_count, &mut client.withdraw_volume),
+        };
+        *count = count.saturating_sub(1);
+        if let Some(amount) = transaction.amount() {
+            *volume = volume.checked_sub(amount).unwrap_or(Amount::ZERO);
+        }
+
+        #[cfg(feature = "wal")]
+        self.write_ahead(&WalEntry::Void { void: tx })?;
+
+        self.transactions.remove(tx);
+        self.fees.remove(&tx);
+        self.requested.remove(&tx);
+        if let Some(history) = self.history.get_mut(&transaction.client) {
+            history.retain(|id| *id != tx);
+        }
+        self.log.retain(|logged| logged.tx != tx);
+        self.settled.remove(&tx);
+        if let (Some(volume), Some(amount)) = (
+            self.volumes.get_mut(&transaction.client),
+            transaction.amount(),
+        ) {
+            let sum = match transaction.ttype {
+                TransactionType::Deposit(_) => &mut volume.deposited,
+                _ => &mut volume.withdrawn,
+            };
+            *sum = sum.checked_sub(amount).unwrap_or(Amount::ZERO);
+        }
+        self.registry.register(client);
+        self.collected_fees = collected_fees;
+        if self.config.max_total_liabilities.is_some() {
+            self.liabilities = self.total_liabilities();
+        }
+        Ok(())
+    }
+
+    /// Credit or debit a client outside the regular transaction flow, e.g. for
+    /// a goodwill credit. A positive `delta` adds to the available and total
+    /// funds of the client, a negative one removes from them. The adjustment
+    /// is recorded together with `note`, see `Exchange::adjustments`.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::InvalidAmount` if `delta` has more than four
+    /// places past the decimal, `ExchangeError::Locked` if the client is
+    /// locked and `ExchangeError::ArithmeticError` if the adjustment would
+    /// overdraw the available funds of the client. Returns
+    /// `ExchangeError::Io` if the adjustment cannot be written to the
+    /// write-ahead log, see `Exchange::set_wal`.
+    pub fn apply_adjustment(
+        &mut self,
+        id: C,
+        delta: Balance,
+        note: String,
+    ) -> Result<(), ExchangeError<C, T>> {
+        Amount::<PRECISION>::try_new(delta.abs())
+            .map_err(|err| ExchangeError::InvalidAmount(delta.to_string(), err.to_string()))?;
+        let mut client = self.load_client(id)?;
+        let arithmetic_error = |message: String| ExchangeError::ArithmeticError(id, message);
+        let available = client
+            .available
+            .checked_add(delta)
+            .ok_or_else(|| arithmetic_error(format!("Adjustment by {delta} overflows")))?;
+        if delta.is_sign_negative() && available < Balance::ZERO {
+            return Err(arithmetic_error(format!(
+                "Adjustment by {delta} would overdraw the available funds. Available: {}",
+                client.available
+            )));
+        }
+        client.available = available;
+        client.total = client
+            .total
+            .checked_add(delta)
+            .ok_or_else(|| arithmetic_error(format!("Adjustment by {delta} overflows")))?;
+
+        let adjustment = Adjustment {
+            client: id,
+            delta,
+            note,
+        };
+        #[cfg(feature = "wal")]
+        self.write_ahead(&WalEntry::Adjustment {
+            adjustment: adjustment.clone(),
+        })?;
+
+        self.registry.register(client);
+        if self.config.max_total_liabilities.is_some() {
+            self.liabilities += delta;
+        }
+        self.adjustments.push(adjustment);
+        Ok(())
+    }
+
+    /// Returns an iterator over all administrative adjustments, in the order
+    /// they were applied, see `Exchange::apply_adjustment`
+    pub fn adjustments(&self) -> impl Iterator<Item = &Adjustment<C>> {
+        self.adjustments.iter()
+    }
+
+    /// Append an entry to the write-ahead log, if there is one, before the
+    /// change it describes gets applied
+    #[cfg(feature = "wal")]
+    fn write_ahead(&mut self, entry: &WalEntry<C, T>) -> Result<(), ExchangeError<C, T>> {
+        match &mut self.wal {
+            Some(wal) => wal
+                .append(entry)
+                .map_err(|err| ExchangeError::Io(err.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Whether a client has stored transactions or adjustments
+    fn has_activity(&self, id: &C) -> bool {
+        self.history.contains_key(id)
+            || self
+                .adjustments
+                .iter()
+                .any(|adjustment| adjustment.client == *id)
+    }
+
+    fn set_locked(&mut self, id: C, locked: bool) -> Result<(), ExchangeError<C, T>> {
+        // Bypass `Registry::get_mut`, which refuses to hand out locked clients
+        let client = self
+            .registry
+            .clients
+            .get_mut(&id)
+            .ok_or(ExchangeError::UnknownClient(id))?;
+        client.locked = locked;
+        Ok(())
+    }
+
+    /// Merge an independently processed exchange into this one, e.g. after
+    /// sharding the input by client ID.
+    ///
+    /// Clients and transactions of both exchanges must be disjoint. A client may
+    /// only appear in both if it has no transactions or adjustments in one of
+    /// them, e.g. if it was only seen in rejected transactions; it is then
+    /// locked if it is locked in either exchange. The configuration and custom
+    /// transaction handlers of this exchange are kept.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::ClientConflict` if a client has transactions or
+    /// adjustments in both exchanges, or an `InvalidTransaction` error with
+    /// `ErrorKind::DuplicateId` if a transaction ID is used in both. The exchange
+    /// is not modified in that case.
+    pub fn merge(
+        &mut self,
+        other: Exchange<BTreeMap<T, Transaction<C, T>>, C, T>,
+    ) -> Result<(), ExchangeError<C, T>> {
+        if let Some(transaction) = other
+            .transactions
+            .values()
+            .find(|transaction| self.transactions.contains(transaction.tx))
+        {
+            return Err(ExchangeError::InvalidTransaction(
+                *transaction,
+                ErrorKind::DuplicateId,
+                "The given transaction ID is used in both exchanges".to_string(),
+            ));
+        }
+        if let Some(id) = other
+            .registry
+            .clients
+            .keys()
+            .find(|id| other.has_activity(id) && self.has_activity(id))
+        {
+            return Err(ExchangeError::ClientConflict(*id));
+        }
+
+        for (id, mut client) in other.registry.clients {
+            if let Some(existing) = self.registry.get(&id) {
+                // Only one of them has activity, so the other is empty
+                let locked = client.locked || existing.locked;
+                if self.has_activity(&id) {
+                    client = *existing;
+                }
+                client.locked = locked;
+            }
+            self.registry.register(client);
+        }
+        for transaction in other.transactions.into_values() {
+            self.transactions.insert(transaction);
+        }
+        self.history.extend(other.history);
+        self.log.extend(other.log);
+        self.disputes.extend(other.disputes);
+        self.fees.extend(other.fees);
+        self.requested.extend(other.requested);
+        self.settled.extend(other.settled);
+        self.charged_back.extend(other.charged_back);
+        self.blocked.extend(other.blocked);
+        self.volumes.extend(other.volumes);
+        self.holds.extend(other.holds);
+        self.adjustments.extend(other.adjustments);
+        self.collected_fees += other.collected_fees;
+        self.swept += other.swept;
+        if self.config.max_total_liabilities.is_some() {
+            self.liabilities = self.total_liabilities();
+        }
+        Ok(())
+    }
+
+    /// Commit a batch of transactions to the exchange.
+    ///
+    /// Every transaction is handled in order; valid ones are committed as they
+    /// go, invalid ones are skipped. Returns every rejected transaction
+    /// together with the error it caused.
+    pub fn handle_all<I: IntoIterator<Item = Transaction<C, T>>>(
+        &mut self,
+        transactions: I,
+    ) -> Vec<(Transaction<C, T>, ExchangeError<C, T>)> {
+        transactions
+            .into_iter()
+            .filter_map(|transaction| {
+                self.handle(&transaction)
+                    .err()
+                    .map(|err| (transaction, err))
+            })
+            .collect()
+    }
+
+    /// Commit a batch of transactions to the exchange like `handle_all`, but
+    /// return a record for each rejected transaction, stating which rule
+    /// rejected it.
+    pub fn apply<I: IntoIterator<Item = Transaction<C, T>>>(
+        &mut self,
+        transactions: I,
+    ) -> Vec<RejectionRecord<C, T>> {
+        self.handle_all(transactions)
+            .into_iter()
+            .map(|(transaction, err)| RejectionRecord::new(transaction, &err))
+            .collect()
+    }
+
+    /// Wipe all clients and recompute their state from scratch by applying
+    /// every accepted transaction again in the order it was accepted (see
+    /// `Exchange::transactions_in_order`), e.g. to confirm that the
+    /// incremental state matches when debugging a balance discrepancy.
+    ///
+    /// Only transactions are replayed. Clients keep whether they are locked,
+    /// as locks and unlocks are not part of the log; blocked clients are
+    /// replayed like any other. Closed accounts are opened again. The
+    /// transactions are neither written to the write-ahead log nor passed to
+    /// the observer again.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::Adjusted` if any adjustments were applied, as
+    /// their order relative to the transactions is not known, or the error of
+    /// the first transaction that is rejected on replay, e.g. because its
+    /// client was unlocked with `Exchange::unlock_client` before. The exchange
+    /// is not modified in either case.
+    pub fn reprocess(&mut self) -> Result<(), ExchangeError<C, T>> {
+        if let Some(adjustment) = self.adjustments.first() {
+            return Err(ExchangeError::Adjusted(adjustment.client));
+        }
+        let mut rebuilt = Exchange::with_store_and_config(BTreeMap::new(), self.config);
+        rebuilt.handlers = std::mem::take(&mut self.handlers);
+        let replayed = self.log.iter().try_for_each(|transaction| {
+            let changes = rebuilt.stage(transaction)?;
+            rebuilt.commit(*transaction, changes);
+            Ok(())
+        });
+        self.handlers = std::mem::take(&mut rebuilt.handlers);
+        replayed?;
+
+        for client in rebuilt.registry.clients.values_mut() {
+            if let Some(existing) = self.registry.get(&client.id) {
+                client.locked = existing.locked;
+            }
+        }
+        // Transactions evicted before are stored again, like their settlement
+        for transaction in rebuilt.transactions.into_values() {
+            self.transactions.insert(transaction);
+        }
+        self.registry = rebuilt.registry;
+        self.history = rebuilt.history;
+        self.disputes = rebuilt.disputes;
+        self.fees = rebuilt.fees;
+        self.settled = rebuilt.settled;
+        self.charged_back = rebuilt.charged_back;
+        self.volumes = rebuilt.volumes;
+        self.holds = rebuilt.holds;
+        self.liabilities = rebuilt.liabilities;
+        self.collected_fees = rebuilt.collected_fees;
+        Ok(())
+    }
+
+    /// Verify that every client in the exchange is in a consistent state, i.e.
+    /// `total == available + held` and no funds are negative, except for
+    /// available funds under `NegativeBalancePolicy::Allow`. See
+    /// `Client::verify`.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::InvariantViolation` for the first inconsistent
+    /// client that was found
+    pub fn verify_invariants(&self) -> Result<(), ExchangeError<C, T>> {
+        let policy = self.config.negative_balance;
+        self.registry
+            .clients
+            .values()
+            .try_for_each(|client| client.verify(policy))
+    }
+
     /// For some transactions the transaction id must be unique
     /// Check that the given id is available
-    fn assert_id_available(&self, transaction: &Transaction) -> Result<(), ExchangeError> {
-        if self.transactions.contains_key(&transaction.tx) {
+    fn assert_id_available(
+        &self,
+        transaction: &Transaction<C, T>,
+    ) -> Result<(), ExchangeError<C, T>> {
+        if self.transactions.contains(transaction.tx) {
             return Err(ExchangeError::InvalidTransaction(
                 *transaction,
+                ErrorKind::DuplicateId,
                 "The transaction ID already exists".to_string(),
             ));
         }
         Ok(())
     }
 
-    /// Look up a certain transaction
-    fn get_tx(&self, transaction: &Transaction) -> Result<Transaction, ExchangeError> {
-        match self.transactions.get(&transaction.tx) {
-            Some(prev_tx) => Ok(*prev_tx),
+    /// Look up the transaction referenced by a dispute, resolve or
+    /// chargeback. It must belong to the same client.
+    fn get_tx(
+        &self,
+        transaction: &Transaction<C, T>,
+    ) -> Result<Transaction<C, T>, ExchangeError<C, T>> {
+        match self.transactions.get(transaction.tx) {
+            Some(prev_tx) if prev_tx.client != transaction.client => {
+                Err(ExchangeError::InvalidTransaction(
+                    *transaction,
+                    ErrorKind::ClientMismatch,
+                    format!(
+                        "The referenced transaction belongs to client {}",
+                        prev_tx.client
+                    ),
+                ))
+            }
+            Some(prev_tx) => Ok(prev_tx),
             None => Err(ExchangeError::InvalidTransaction(
                 *transaction,
+                ErrorKind::UnknownTransaction,
                 "The given transaction ID DOES NOT exist".to_string(),
             )),
         }
@@ -64,189 +886,3502 @@ impl Exchange {
 
     /// Commit a transaction to the exchange.
     ///
+    /// The transaction is applied atomically: all balance changes are staged on
+    /// a copy of the client and only committed once every check passed. If the
+    /// transaction is rejected, neither the client nor the stored transactions
+    /// are modified. (The client record itself still gets created, as every
+    /// client ID seen by the exchange is part of the output.)
+    ///
+    /// Returns the state of the client of the transaction after the change.
+    /// For transfers, this is the source of the funds.
+    ///
     /// ## Errors
     ///
     /// Returns error in case of an invalid transaction
-    pub fn handle(&mut self, transaction: Transaction) -> Result<(), ExchangeError> {
+    pub fn handle(
+        &mut self,
+        transaction: &Transaction<C, T>,
+    ) -> Result<Client<C>, ExchangeError<C, T>> {
+        let outcome = self.process(transaction);
+        self.observer.notify(transaction, outcome.as_ref());
+        outcome
+    }
+
+    /// Stage, log and commit a transaction, see `handle`
+    fn process(
+        &mut self,
+        transaction: &Transaction<C, T>,
+    ) -> Result<Client<C>, ExchangeError<C, T>> {
+        let staged = self.stage(transaction);
+        // Log the transaction before applying it, so that no accepted
+        // transaction is lost in a crash
+        #[cfg(feature = "wal")]
+        let staged = staged.and_then(|changes| {
+            self.write_ahead(&WalEntry::Transaction(*transaction))
+                .map(|()| changes)
+        });
+        let changes = match staged {
+            Ok(changes) => changes,
+            Err(err) => {
+                // Clients get created even by rejected transactions, except
+                // by disputes and their outcomes, which can only refer to
+                // existing clients. Locked clients exist already, so the
+                // error can be ignored.
+                if !refers_to_transaction(transaction) {
+                    let _ = self.registry.get_mut::<T>(&transaction.client);
+                }
+                return Err(err);
+            }
+        };
+        self.commit(*transaction, changes);
+        Ok(changes.client)
+    }
+
+    /// Commit a transaction like `handle`, but accept exact duplicates of
+    /// stored transactions without applying them again, e.g. when a stream is
+    /// replayed after a network retry.
+    ///
+    /// Returns the current state of the client of the transaction for
+    /// duplicates.
+    ///
+    /// ## Errors
+    ///
+    /// Returns error in case of an invalid transaction. Transactions reusing
+    /// the ID of a different transaction are still rejected with
+    /// `ErrorKind::DuplicateId`.
+    pub fn handle_idempotent(
+        &mut self,
+        transaction: &Transaction<C, T>,
+    ) -> Result<Client<C>, ExchangeError<C, T>> {
+        if self.submitted(transaction.tx).as_ref() == Some(transaction) {
+            return Ok(self
+                .registry
+                .get(&transaction.client)
+                .copied()
+                .unwrap_or_else(|| Client::new(transaction.client)));
+        }
+        self.handle(transaction)
+    }
+
+    /// A stored transaction as it was submitted, i.e. with the requested
+    /// amount of a partially filled withdrawal instead of the filled one
+    fn submitted(&self, tx: T) -> Option<Transaction<C, T>> {
+        let transaction = self.transactions.get(tx)?;
+        Some(match self.requested.get(&tx) {
+            Some(requested) => Transaction {
+                ttype: TransactionType::Withdraw(*requested),
+                ..transaction
+            },
+            None => transaction,
+        })
+    }
+
+    /// Check whether a transaction would be accepted by `handle`, without
+    /// modifying the exchange
+    ///
+    /// ## Errors
+    ///
+    /// Returns the error `handle` would return for the transaction
+    pub fn validate(&self, transaction: &Transaction<C, T>) -> Result<(), ExchangeError<C, T>> {
+        self.stage(transaction).map(|_| ())
+    }
+
+    /// Copy of the current state of a client, or a new client if the exchange
+    /// doesn't know it yet
+    fn load_client(&self, id: C) -> Result<Client<C>, ExchangeError<C, T>> {
+        match self.registry.get(&id) {
+            Some(client) if client.locked => Err(ExchangeError::Locked(*client)),
+            Some(client) => Ok(*client),
+            None => Ok(Client::new(id)),
+        }
+    }
+
+    /// Copy of the current state of a client that must exist already, e.g.
+    /// for a dispute of one of its transactions
+    fn load_existing_client(&self, id: C) -> Result<Client<C>, ExchangeError<C, T>> {
+        self.registry.get_existing(&id).copied()
+    }
+
+    /// Validate a transaction and compute the changes it causes, without
+    /// modifying any balances
+    pub(crate) fn stage(
+        &self,
+        transaction: &Transaction<C, T>,
+    ) -> Result<Changes<C>, ExchangeError<C, T>> {
+        if self.blocked.contains(&transaction.client) {
+            return Err(ExchangeError::Blocked(transaction.client));
+        }
+        if let TransactionType::Transfer { to, .. } = transaction.ttype {
+            if self.blocked.contains(&to) {
+                return Err(ExchangeError::Blocked(to));
+            }
+        }
+        let mut changes = self.stage_balances(transaction)?;
+
+        // Refuse to commit corrupt balances, which would indicate a bug
+        if cfg!(debug_assertions) || self.config.check_invariants {
+            changes.client.verify_total()?;
+            if let Some(counterparty) = changes.counterparty {
+                counterparty.verify_total()?;
+            }
+        }
+
+        let Some(max) = self.config.max_total_liabilities else {
+            return Ok(changes);
+        };
+
+        // Keep track of the total liabilities of the exchange
+        let mut liabilities = self.liabilities;
+        for client in std::iter::once(changes.client).chain(changes.counterparty) {
+            let previous = self
+                .registry
+                .get(&client.id)
+                .map_or(Balance::ZERO, |c| c.total);
+            liabilities = add(liabilities, client.total, transaction)?;
+            liabilities = sub(liabilities, previous, transaction)?;
+        }
+        if matches!(transaction.ttype, TransactionType::Deposit(_)) && liabilities > max.value() {
+            return Err(ExchangeError::InvalidTransaction(
+                *transaction,
+                ErrorKind::LiabilityLimit,
+                format!(
+                    "Deposit exceeds the limit of total liabilities. Limit: {max}, total liabilities: {liabilities}"
+                ),
+            ));
+        }
+        changes.liabilities = liabilities;
+        Ok(changes)
+    }
+
+    /// Compute the new balances of the clients involved in a transaction
+    fn stage_balances(
+        &self,
+        transaction: &Transaction<C, T>,
+    ) -> Result<Changes<C>, ExchangeError<C, T>> {
         match transaction.ttype {
             TransactionType::Deposit(amount) => {
-                self.assert_id_available(&transaction)?;
-                self.transactions.insert(transaction.tx, transaction);
-                let client = self.registry.get_mut(&transaction.client)?;
-                client.total += amount;
-                client.available += amount;
+                let client = self.load_client(transaction.client)?;
+                self.assert_id_available(transaction)?;
+                let client = credit(client, amount, transaction)?;
+                Ok(Changes {
+                    volume: self.stage_volume(transaction, amount)?,
+                    ..Changes::new(count_activity(client, amount, transaction))
+                })
             }
-            TransactionType::Withdraw(amount) => {
-                self.assert_id_available(&transaction)?;
-                self.transactions.insert(transaction.tx, transaction);
-                let client = self.registry.get_mut(&transaction.client)?;
-                if client.available < amount {
-                    return Err(ExchangeError::InvalidTransaction(
-                        transaction,
-                        format!(
-                        "Insufficient funds available for transaction. Available: {}, required: {}",
-                        client.available, amount
-                    ),
-                    ));
-                }
-                client.total -= amount;
-                client.available -= amount;
-            }
-            TransactionType::Dispute => {
-                let prev_tx = self.get_tx(&transaction)?;
-                let client = self.registry.get_mut(&transaction.client)?;
-                match prev_tx.ttype {
-                    TransactionType::Deposit(amount) | TransactionType::Withdraw(amount) => {
-                        client.available -= amount;
-                        client.held += amount;
-                    }
-                    _ => {
-                        return Err(ExchangeError::InvalidTransaction(
-                            transaction,
-                            "Given transaction was not a deposit or withdrawal and thus has no amount".to_string(),
-                        ));
-                    }
-                };
-            }
-            TransactionType::Resolve => {
-                let prev_tx = self.get_tx(&transaction)?;
-                if let Some(amount) = prev_tx.amount() {
-                    let client = self.registry.get_mut(&transaction.client)?;
-                    client.held -= amount;
-                    client.available += amount;
-                } else {
-                    return Err(ExchangeError::InvalidTransaction(
-                        transaction,
-                        "No amount associated with transaction".to_string(),
-                    ));
-                }
+            TransactionType::Withdraw(amount) => self.stage_withdrawal(transaction, amount),
+            TransactionType::Transfer { to, amount } => {
+                self.stage_transfer(transaction, to, amount)
             }
+            TransactionType::Dispute(amount) => self.stage_dispute(transaction, amount),
+            TransactionType::Resolve(amount) => self.stage_resolve(transaction, amount),
             TransactionType::Chargeback => {
-                let prev_tx = self.get_tx(&transaction)?;
-                if let Some(amount) = prev_tx.amount() {
-                    let client = self.registry.get_mut(&transaction.client)?;
-                    client.held -= amount;
-                    client.total -= amount;
-                    client.locked = true;
+                let (prev_tx, held) = self.open_dispute(transaction)?;
+                let mut client = self.load_existing_client(transaction.client)?;
+                client.held = release(client.held, held, transaction)?;
+                if is_withdrawal(&prev_tx) {
+                    // The withdrawn funds get returned to the client
+                    client.available = add(client.available, held, transaction)?;
                 } else {
-                    return Err(ExchangeError::InvalidTransaction(
-                        transaction,
-                        "No amount associated with transaction".to_string(),
+                    // The deposited funds leave the exchange
+                    client.total = sub(client.total, held, transaction)?;
+                }
+                client.locked = true;
+                Ok(Changes::new(client))
+            }
+            TransactionType::Hold(amount) => {
+                let mut client = self.load_client(transaction.client)?;
+                self.assert_id_available(transaction)?;
+                self.assert_free_funds(&client, amount, transaction)?;
+                let held = self
+                    .admin_held(transaction.client)
+                    .checked_add(amount)
+                    .ok_or(ExchangeError::Overflow(*transaction))?;
+                client.available = sub(client.available, amount, transaction)?;
+                client.held = add(client.held, amount, transaction)?;
+                Ok(Changes {
+                    hold: Some(held),
+                    ..Changes::new(client)
+                })
+            }
+            TransactionType::Release(amount) => {
+                let mut client = self.load_client(transaction.client)?;
+                self.assert_id_available(transaction)?;
+                let admin_held = self.admin_held(transaction.client);
+                // Funds held for disputes can only be freed by a resolve
+                let held = admin_held.checked_sub(amount).ok_or_else(|| {
+                    ExchangeError::InvalidTransaction(
+                        *transaction,
+                        ErrorKind::NegativeBalance,
+                        format!(
+                            "Release exceeds the funds held by operators. Held: {admin_held}, released: {amount}"
+                        ),
+                    )
+                })?;
+                client.held = release(client.held, amount, transaction)?;
+                client.available = add(client.available, amount, transaction)?;
+                Ok(Changes {
+                    hold: Some(held),
+                    ..Changes::new(client)
+                })
+            }
+            TransactionType::Custom(name, amount) => {
+                let mut client = self.load_client(transaction.client)?;
+                self.assert_id_available(transaction)?;
+                let handler = self.handlers.get(name).ok_or_else(|| {
+                    ExchangeError::InvalidTransaction(
+                        *transaction,
+                        ErrorKind::UnsupportedType,
+                        format!("No handler registered for transaction type `{name}`"),
+                    )
+                })?;
+                let before = client;
+                handler(&mut client, amount)?;
+                if client.id != before.id || client.locked != before.locked {
+                    return Err(ExchangeError::InvariantViolation(
+                        client,
+                        "Custom handlers may not change the ID or lock of a client".to_string(),
                     ));
                 }
+                client.verify(self.config.negative_balance)?;
+                Ok(Changes::new(client))
             }
         }
-        Ok(())
     }
-}
 
-impl Default for Exchange {
-    fn default() -> Self {
-        Self::new()
+    /// Debit a withdrawal and its fee. With
+    /// `ExchangeConfig::partial_withdrawals`, a withdrawal exceeding the
+    /// available funds withdraws as much as they cover instead.
+    fn stage_withdrawal(
+        &self,
+        transaction: &Transaction<C, T>,
+        amount: Amount,
+    ) -> Result<Changes<C>, ExchangeError<C, T>> {
+        let client = self.load_client(transaction.client)?;
+        self.assert_id_available(transaction)?;
+        let free = self.free_funds(&client, transaction)?;
+        let mut filled = amount;
+        let mut charged = self.charge_for(transaction, amount)?;
+        if self.config.partial_withdrawals && charged.value() > free {
+            // If nothing can be withdrawn, the withdrawal is rejected below
+            let max = self.max_withdrawal(free);
+            if !max.is_zero() {
+                filled = max;
+                charged = self.charge_for(transaction, filled)?;
+            }
+        }
+        if charged.value() > free {
+            return Err(ExchangeError::InsufficientFunds {
+                client: client.id,
+                available: free,
+                requested: charged,
+            });
+        }
+        let volume = self.stage_volume(transaction, filled)?;
+        Ok(Changes {
+            fee: charged - filled,
+            volume,
+            filled: (filled != amount).then_some(filled),
+            ..Changes::new(count_activity(
+                debit(client, charged, transaction)?,
+                filled,
+                transaction,
+            ))
+        })
     }
-}
 
-#[cfg(test)]
-mod test_exchange {
-    use crate::Amount;
+    /// The available funds of a client less `ClientLimits::min_balance`,
+    /// which withdrawals, transfers and holds may take
+    fn free_funds(
+        &self,
+        client: &Client<C>,
+        transaction: &Transaction<C, T>,
+    ) -> Result<Balance, ExchangeError<C, T>> {
+        let reserve = self.config.limits.min_balance.unwrap_or_default();
+        sub(client.available, reserve, transaction)
+    }
 
-    use super::*;
+    /// Fail with `ExchangeError::InsufficientFunds` if `amount` exceeds the
+    /// free funds of a client, see `Exchange::free_funds`
+    fn assert_free_funds(
+        &self,
+        client: &Client<C>,
+        amount: Amount,
+        transaction: &Transaction<C, T>,
+    ) -> Result<(), ExchangeError<C, T>> {
+        let free = self.free_funds(client, transaction)?;
+        if amount.value() > free {
+            return Err(ExchangeError::InsufficientFunds {
+                client: client.id,
+                available: free,
+                requested: amount,
+            });
+        }
+        Ok(())
+    }
 
-    #[test]
-    fn test_deposit() {
-        let mut exchange = Exchange::new();
-        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
-        assert!(exchange.handle(tx).is_ok());
-        let client = exchange.get_client(1).unwrap();
-        assert_eq!(client.total, Amount::new(1000, 0));
-        assert_eq!(client.available, Amount::new(1000, 0));
-        assert_eq!(client.held, Amount::new(0, 0));
-        assert_eq!(client.locked, false);
+    /// Funds held for a client by operators, not including funds held for
+    /// disputes
+    fn admin_held(&self, id: C) -> Amount {
+        self.holds.get(&id).copied().unwrap_or(Amount::ZERO)
     }
 
-    #[test]
+    /// The amount charged for withdrawing `amount`, including the fee
+    fn charge_for(
+        &self,
+        transaction: &Transaction<C, T>,
+        amount: Amount,
+    ) -> Result<Amount, ExchangeError<C, T>> {
+        let fee = match self.config.withdrawal_fee {
+            Some(fee) => fee
+                .fee_for(amount)
+                .ok_or(ExchangeError::Overflow(*transaction))?,
+            None => Amount::ZERO,
+        };
+        amount
+            .checked_add(fee)
+            .ok_or(ExchangeError::Overflow(*transaction))
+    }
+
+    /// The largest amount that can be withdrawn from `available` funds,
+    /// including the fee
+    fn max_withdrawal(&self, available: Balance) -> Amount {
+        let fee = self.config.withdrawal_fee.unwrap_or_default();
+        let Some(net) = available
+            .checked_sub(fee.fixed.value())
+            .filter(|net| *net > Balance::ZERO)
+        else {
+            return Amount::ZERO;
+        };
+        let rate = Balance::ONE + fee.percentage.value() / Balance::new(100, 0);
+        let mut filled = Amount::try_new(
+            (net / rate).round_dp_with_strategy(PRECISION, RoundingStrategy::ToZero),
+        )
+        .unwrap_or(Amount::ZERO);
+        // The fee is rounded, which may push the charged amount above the
+        // available funds
+        let step = Amount::new(1, PRECISION);
+        while fee
+            .fee_for(filled)
+            .and_then(|fee| filled.checked_add(fee))
+            .is_none_or(|charged| charged.value() > available)
+        {
+            match filled.checked_sub(step) {
+                Some(smaller) => filled = smaller,
+                None => return Amount::ZERO,
+            }
+        }
+        filled
+    }
+
+    /// Check a deposit or withdrawal against `ExchangeConfig::limits`. Returns
+    /// the sums of the deposits and withdrawals of the client afterwards if a
+    /// cumulative limit applies.
+    fn stage_volume(
+        &self,
+        transaction: &Transaction<C, T>,
+        amount: Amount,
+    ) -> Result<Option<Volume>, ExchangeError<C, T>> {
+        let limits = self.config.limits;
+        let mut volume = self
+            .volumes
+            .get(&transaction.client)
+            .copied()
+            .unwrap_or_default();
+        let (name, max, max_total, sum) = match transaction.ttype {
+            TransactionType::Deposit(_) => (
+                "Deposit",
+                limits.max_deposit,
+                limits.max_total_deposits,
+                &mut volume.deposited,
+            ),
+            _ => (
+                "Withdrawal",
+                limits.max_withdrawal,
+                limits.max_total_withdrawals,
+                &mut volume.withdrawn,
+            ),
+        };
+        let limit_error = |message| {
+            ExchangeError::InvalidTransaction(*transaction, ErrorKind::ClientLimit, message)
+        };
+        match max {
+            Some(max) if amount > max => {
+                return Err(limit_error(format!(
+                    "{name} exceeds the limit per transaction. Limit: {max}, amount: {amount}"
+                )));
+            }
+            _ => {}
+        }
+        let Some(max_total) = max_total else {
+            return Ok(None);
+        };
+        *sum = sum
+            .checked_add(amount)
+            .ok_or(ExchangeError::Overflow(*transaction))?;
+        if *sum > max_total {
+            return Err(limit_error(format!(
+                "{name} exceeds the limit per client. Limit: {max_total}, total: {sum}"
+            )));
+        }
+        Ok(Some(volume))
+    }
+
+    fn stage_transfer(
+        &self,
+        transaction: &Transaction<C, T>,
+        to: C,
+        amount: Amount,
+    ) -> Result<Changes<C>, ExchangeError<C, T>> {
+        let source = self.load_client(transaction.client)?;
+        self.assert_id_available(transaction)?;
+        if to == transaction.client {
+            return Err(ExchangeError::InvalidTransaction(
+                *transaction,
+                ErrorKind::SelfTransfer,
+                "Source and destination of a transfer must differ".to_string(),
+            ));
+        }
+        self.assert_free_funds(&source, amount, transaction)?;
+        let source = debit(source, amount, transaction)?;
+        let destination = self.load_client(to)?;
+        let destination = credit(destination, amount, transaction)?;
+        Ok(Changes {
+            counterparty: Some(destination),
+            ..Changes::new(source)
+        })
+    }
+
+    fn stage_dispute(
+        &self,
+        transaction: &Transaction<C, T>,
+        amount: Option<Amount>,
+    ) -> Result<Changes<C>, ExchangeError<C, T>> {
+        let (prev_tx, original) = self.disputed_amount(transaction)?;
+        if is_withdrawal(&prev_tx) && !self.config.allow_withdrawal_disputes {
+            return Err(ExchangeError::InvalidTransaction(
+                *transaction,
+                ErrorKind::DisputeNotAllowed,
+                "Withdrawals cannot be disputed".to_string(),
+            ));
+        }
+        let amount = match amount {
+            Some(amount) if amount > original => {
+                return Err(ExchangeError::InvalidTransaction(
+                    *transaction,
+                    ErrorKind::ExceedsTransaction,
+                    format!(
+                        "Disputed amount exceeds the amount of the transaction. Transaction: {original}, disputed: {amount}"
+                    ),
+                ));
+            }
+            Some(amount) => amount,
+            None => original,
+        };
+        if self.disputes.contains_key(&transaction.tx) {
+            return Err(ExchangeError::InvalidTransaction(
+                *transaction,
+                ErrorKind::AlreadyDisputed,
+                "The transaction is already disputed".to_string(),
+            ));
+        }
+        let mut client = self.load_existing_client(transaction.client)?;
+        let amount = if is_withdrawal(&prev_tx) {
+            // The withdrawn funds already left the account, so they are held
+            // on top of the current funds until the dispute is settled
+            client.total = add(client.total, amount, transaction)?;
+            amount
+        } else {
+            let amount = self.hold_available(&client, amount)?;
+            client.available = sub(client.available, amount, transaction)?;
+            amount
+        };
+        client.held = add(client.held, amount, transaction)?;
+        Ok(Changes {
+            dispute: Some(amount),
+            ..Changes::new(client)
+        })
+    }
+
+    /// The amount to hold for a dispute of a deposit, according to
+    /// `ExchangeConfig::negative_balance`
+    fn hold_available(
+        &self,
+        client: &Client<C>,
+        amount: Amount,
+    ) -> Result<Amount, ExchangeError<C, T>> {
+        if client.available >= amount.value() {
+            return Ok(amount);
+        }
+        match self.config.negative_balance {
+            NegativeBalancePolicy::Allow => Ok(amount),
+            NegativeBalancePolicy::Reject => Err(ExchangeError::ArithmeticError(
+                client.id,
+                format!(
+                    "Holding {amount} would make the available funds negative. Available: {}",
+                    client.available
+                ),
+            )),
+            NegativeBalancePolicy::Clamp => {
+                Ok(Amount::try_new(client.available.max(Balance::ZERO)).unwrap_or(Amount::ZERO))
+            }
+        }
+    }
+
+    fn stage_resolve(
+        &self,
+        transaction: &Transaction<C, T>,
+        amount: Option<Amount>,
+    ) -> Result<Changes<C>, ExchangeError<C, T>> {
+        let (prev_tx, held) = self.open_dispute(transaction)?;
+        let released = match amount {
+            Some(amount) if amount > held => {
+                return Err(ExchangeError::InvalidTransaction(
+                    *transaction,
+                    ErrorKind::ExceedsDisputed,
+                    format!(
+                        "Resolved amount exceeds disputed amount. Disputed: {held}, resolved: {amount}"
+                    ),
+                ));
+            }
+            Some(amount) => amount,
+            None => held,
+        };
+        // Close the dispute once nothing is held anymore
+        let remaining = held - released;
+        let remaining = if remaining.is_zero() {
+            None
+        } else {
+            Some(remaining)
+        };
+        let mut client = self.load_existing_client(transaction.client)?;
+        client.held = release(client.held, released, transaction)?;
+        if is_withdrawal(&prev_tx) {
+            // The withdrawal stands, so its funds stay withdrawn
+            client.total = sub(client.total, released, transaction)?;
+        } else {
+            client.available = add(client.available, released, transaction)?;
+        }
+        Ok(Changes {
+            dispute: remaining,
+            ..Changes::new(client)
+        })
+    }
+
+    /// Apply the staged changes of a valid transaction
+    pub(crate) fn commit(&mut self, transaction: Transaction<C, T>, changes: Changes<C>) {
+        // Store the amount that was actually withdrawn, so disputes refer to it
+        let transaction = match (changes.filled, transaction.ttype) {
+            (Some(filled), TransactionType::Withdraw(requested)) => {
+                self.requested.insert(transaction.tx, requested);
+                Transaction {
+                    ttype: TransactionType::Withdraw(filled),
+                    ..transaction
+                }
+            }
+            _ => transaction,
+        };
+        match transaction.ttype {
+            TransactionType::Dispute(_)
+            | TransactionType::Resolve(_)
+            | TransactionType::Chargeback => {
+                if let Some(held) = changes.dispute {
+                    self.disputes.insert(transaction.tx, held);
+                    self.settled.remove(&transaction.tx);
+                } else {
+                    self.disputes.remove(&transaction.tx);
+                    self.settled.insert(transaction.tx);
+                }
+                if transaction.ttype == TransactionType::Chargeback {
+                    self.charged_back.insert(transaction.tx);
+                }
+            }
+            _ => {
+                self.transactions.insert(transaction);
+                if changes.fee > Amount::ZERO {
+                    self.fees.insert(transaction.tx, changes.fee);
+                }
+                self.history
+                    .entry(transaction.client)
+                    .or_default()
+                    .push(transaction.tx);
+            }
+        }
+        self.log.push(transaction);
+        self.registry.register(changes.client);
+        if let Some(counterparty) = changes.counterparty {
+            self.registry.register(counterparty);
+        }
+        if self.config.max_total_liabilities.is_some() {
+            self.liabilities = changes.liabilities;
+        }
+        self.collected_fees += changes.fee;
+        if let Some(volume) = changes.volume {
+            self.volumes.insert(transaction.client, volume);
+        }
+        match changes.hold {
+            Some(held) if held == Amount::ZERO => {
+                self.holds.remove(&transaction.client);
+            }
+            Some(held) => {
+                self.holds.insert(transaction.client, held);
+            }
+            None => {}
+        }
+        if let Some(max) = self.config.max_transactions {
+            if self.transactions.len() > max {
+                self.evict(max);
+            }
+        }
+    }
+
+    /// Remove all transactions whose dispute was resolved or charged back from
+    /// the store, to free memory in long-running processes. The balances of
+    /// their clients are kept, but evicted transactions can no longer be
+    /// disputed, and their IDs can be used again. They stay part of
+    /// `Exchange::transactions_in_order`. Returns the number of evicted
+    /// transactions.
+    ///
+    /// With `ExchangeConfig::max_transactions`, settled transactions get
+    /// evicted automatically, in ascending order of their IDs, once the store
+    /// exceeds the limit.
+    pub fn evict_settled(&mut self) -> usize {
+        self.evict(0)
+    }
+
+    /// Evict settled transactions until at most `limit` transactions are
+    /// stored or none are left to evict
+    fn evict(&mut self, limit: usize) -> usize {
+        let mut evicted = 0;
+        while self.transactions.len() > limit {
+            let Some(tx) = self.settled.pop_first() else {
+                break;
+            };
+            if let Some(transaction) = self.transactions.remove(tx) {
+                self.fees.remove(&tx);
+                self.requested.remove(&tx);
+                self.charged_back.remove(&tx);
+                if let Some(history) = self.history.get_mut(&transaction.client) {
+                    history.retain(|id| *id != tx);
+                }
+                evicted += 1;
+            }
+        }
+        evicted
+    }
+
+    /// Look up the transaction referenced by a resolve or chargeback and the
+    /// amount still held for its dispute
+    fn open_dispute(
+        &self,
+        transaction: &Transaction<C, T>,
+    ) -> Result<(Transaction<C, T>, Amount), ExchangeError<C, T>> {
+        let prev_tx = self.get_tx(transaction)?;
+        match self.disputes.get(&transaction.tx) {
+            Some(held) => Ok((prev_tx, *held)),
+            None => Err(ExchangeError::InvalidTransaction(
+                *transaction,
+                ErrorKind::NotDisputed,
+                "The transaction is not disputed".to_string(),
+            )),
+        }
+    }
+
+    /// Look up the transaction referenced by a dispute and its amount.
+    /// Only deposits and withdrawals can be disputed.
+    fn disputed_amount(
+        &self,
+        transaction: &Transaction<C, T>,
+    ) -> Result<(Transaction<C, T>, Amount), ExchangeError<C, T>> {
+        let prev_tx = self.get_tx(transaction)?;
+        match prev_tx.ttype {
+            TransactionType::Deposit(amount) | TransactionType::Withdraw(amount) => {
+                Ok((prev_tx, amount))
+            }
+            _ => Err(ExchangeError::InvalidTransaction(
+                *transaction,
+                ErrorKind::NotAmountBearing,
+                "Given transaction was not a deposit or withdrawal and thus has no amount"
+                    .to_string(),
+            )),
+        }
+    }
+}
+
+/// Changes caused by a transaction, staged until all checks passed
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Changes<C> {
+    /// Updated state of the client of the transaction
+    client: Client<C>,
+    /// Updated state of another client involved in the transaction, i.e. the
+    /// destination of a transfer
+    counterparty: Option<Client<C>>,
+    /// Amount held for the referenced transaction afterwards, if still
+    /// disputed
+    dispute: Option<Amount>,
+    /// Total liabilities of the exchange afterwards
+    liabilities: Balance,
+    /// Fee collected by the exchange
+    fee: Amount,
+    /// Sums of the deposits and withdrawals of the client afterwards, if
+    /// tracked
+    volume: Option<Volume>,
+    /// Amount withdrawn by a partially filled withdrawal
+    filled: Option<Amount>,
+    /// Funds held by operators for the client afterwards, if changed
+    hold: Option<Amount>,
+}
+
+impl<C> Changes<C> {
+    const fn new(client: Client<C>) -> Self {
+        Self {
+            client,
+            counterparty: None,
+            dispute: None,
+            liabilities: Balance::ZERO,
+            fee: Amount::ZERO,
+            volume: None,
+            filled: None,
+            hold: None,
+        }
+    }
+}
+
+/// Sums of the deposits and withdrawals of a client, see `ClientLimits`
+#[derive(Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub(crate) struct Volume {
+    deposited: Amount,
+    withdrawn: Amount,
+}
+
+/// Whether the transaction refers to an earlier transaction, i.e. is a
+/// dispute, resolve or chargeback
+const fn refers_to_transaction<C, T>(transaction: &Transaction<C, T>) -> bool {
+    matches!(
+        transaction.ttype,
+        TransactionType::Dispute(_) | TransactionType::Resolve(_) | TransactionType::Chargeback
+    )
+}
+
+/// Whether a disputed transaction is a withdrawal. Disputing a withdrawal
+/// holds the withdrawn funds on top of the client's current funds, while
+/// disputing a deposit holds part of the available funds.
+const fn is_withdrawal<C, T>(transaction: &Transaction<C, T>) -> bool {
+    matches!(transaction.ttype, TransactionType::Withdraw(_))
+}
+
+/// Add funds to the available and total funds of a client
+fn credit<C: Id, T: Id>(
+    mut client: Client<C>,
+    amount: Amount,
+    transaction: &Transaction<C, T>,
+) -> Result<Client<C>, ExchangeError<C, T>> {
+    client.total = add(client.total, amount, transaction)?;
+    client.available = add(client.available, amount, transaction)?;
+    Ok(client)
+}
+
+/// Count a deposit or withdrawal of `amount` towards the activity counters of
+/// the client
+fn count_activity<C: Id, T: Id>(
+    mut client: Client<C>,
+    amount: Amount,
+    transaction: &Transaction<C, T>,
+) -> Client<C> {
+    let (count, volume) = match transaction.ttype {
+        TransactionType::Deposit(_) => (&mut client.deposit_count, &mut client.deposit_volume),
+        _ => (&mut client.withdraw_count, &mut client.withdraw_volume),
+    };
+    // The counters are statistics only, so they saturate instead of
+    // rejecting the transaction
+    *count = count.saturating_add(1);
+    *volume = volume.checked_add(amount).unwrap_or(Amount::MAX);
+    client
+}
+
+/// Remove funds from the available and total funds of a client, rejecting the
+/// transaction if not enough funds are available
+fn debit<C: Id, T: Id>(
+    mut client: Client<C>,
+    amount: Amount,
+    transaction: &Transaction<C, T>,
+) -> Result<Client<C>, ExchangeError<C, T>> {
+    if client.available < amount.value() {
+        return Err(ExchangeError::InsufficientFunds {
+            client: client.id,
+            available: client.available,
+            requested: amount,
+        });
+    }
+    client.total = sub(client.total, amount, transaction)?;
+    client.available = sub(client.available, amount, transaction)?;
+    Ok(client)
+}
+
+/// Remove `amount` from the held funds of a client, rejecting the transaction
+/// if they would become negative. This guards against inconsistencies between
+/// the held funds and the tracked disputes.
+fn release<C: Id, T: Id>(
+    held: Balance,
+    amount: Amount,
+    transaction: &Transaction<C, T>,
+) -> Result<Balance, ExchangeError<C, T>> {
+    let held_after = sub(held, amount, transaction)?;
+    if held_after < Balance::ZERO {
+        return Err(ExchangeError::InvalidTransaction(
+            *transaction,
+            ErrorKind::NegativeBalance,
+            format!("Held funds would become negative. Held: {held}, released: {amount}"),
+        ));
+    }
+    Ok(held_after)
+}
+
+/// Add `amount` to a balance, rejecting the transaction if the result would
+/// overflow
+fn add<C: Id, T: Id>(
+    balance: Balance,
+    amount: impl Into<Balance>,
+    transaction: &Transaction<C, T>,
+) -> Result<Balance, ExchangeError<C, T>> {
+    balance
+        .checked_add(amount.into())
+        .ok_or(ExchangeError::Overflow(*transaction))
+}
+
+/// Subtract `amount` from a balance, rejecting the transaction if the result
+/// would overflow
+fn sub<C: Id, T: Id>(
+    balance: Balance,
+    amount: impl Into<Balance>,
+    transaction: &Transaction<C, T>,
+) -> Result<Balance, ExchangeError<C, T>> {
+    balance
+        .checked_sub(amount.into())
+        .ok_or(ExchangeError::Overflow(*transaction))
+}
+
+impl Default for Exchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test_exchange {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::{ClientLimits, WithdrawalFee};
+
+    #[test]
+    fn test_deposit() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.total, Amount::new(1000, 0));
+        assert_eq!(client.available, Amount::new(1000, 0));
+        assert_eq!(client.held, Amount::new(0, 0));
+        assert!(!client.locked);
+    }
+
+    #[test]
     fn test_withdraw_without_prior_deposit() {
         let mut exchange = Exchange::new();
         let tx = Transaction::new(1, 1, TransactionType::Withdraw(Amount::new(1000, 0)));
-        assert!(exchange.handle(tx).is_err());
+        assert!(exchange.handle(&tx).is_err());
         // Transaction failed, but client was created
         let client = exchange.get_client(1).unwrap();
         assert_eq!(client.total, Amount::new(0, 0));
         assert_eq!(client.available, Amount::new(0, 0));
         assert_eq!(client.held, Amount::new(0, 0));
-        assert_eq!(client.locked, false);
+        assert!(!client.locked);
     }
 
     #[test]
     fn test_same_id_rejected() {
         let mut exchange = Exchange::new();
         let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
-        assert!(exchange.handle(tx).is_ok());
+        assert!(exchange.handle(&tx).is_ok());
         let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
-        assert!(exchange.handle(tx).is_err());
+        assert!(exchange.handle(&tx).is_err());
     }
 
     #[test]
     fn test_deposit_withdraw() {
         let mut exchange = Exchange::new();
         let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
-        assert!(exchange.handle(tx).is_ok());
+        assert!(exchange.handle(&tx).is_ok());
         let tx = Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(500, 0)));
-        assert!(exchange.handle(tx).is_ok());
+        assert!(exchange.handle(&tx).is_ok());
         // Transaction failed, but client was created
         let client = exchange.get_client(1).unwrap();
         assert_eq!(client.total, Amount::new(500, 0));
         assert_eq!(client.available, Amount::new(500, 0));
         assert_eq!(client.held, Amount::new(0, 0));
-        assert_eq!(client.locked, false);
+        assert!(!client.locked);
     }
 
     #[test]
     fn test_dispute() {
         let mut exchange = Exchange::new();
         let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
-        assert!(exchange.handle(tx).is_ok());
-        let tx = Transaction::new(1, 1, TransactionType::Dispute);
-        assert!(exchange.handle(tx).is_ok());
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
 
         let client = exchange.get_client(1).unwrap();
         assert_eq!(client.total, Amount::new(1000, 0));
         assert_eq!(client.available, Amount::new(0, 0));
         assert_eq!(client.held, Amount::new(1000, 0));
-        assert_eq!(client.locked, false);
+        assert!(!client.locked);
     }
 
     #[test]
     fn test_resolve() {
         let mut exchange = Exchange::new();
         let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
-        assert!(exchange.handle(tx).is_ok());
-        let tx = Transaction::new(1, 1, TransactionType::Dispute);
-        assert!(exchange.handle(tx).is_ok());
-        let tx = Transaction::new(1, 1, TransactionType::Resolve);
-        assert!(exchange.handle(tx).is_ok());
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Resolve(None));
+        assert!(exchange.handle(&tx).is_ok());
 
         let client = exchange.get_client(1).unwrap();
         assert_eq!(client.total, Amount::new(1000, 0));
         assert_eq!(client.available, Amount::new(1000, 0));
         assert_eq!(client.held, Amount::new(0, 0));
-        assert_eq!(client.locked, false);
+        assert!(!client.locked);
     }
 
     #[test]
     fn test_chargeback() {
         let mut exchange = Exchange::new();
         let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
-        assert!(exchange.handle(tx).is_ok());
-        let tx = Transaction::new(1, 1, TransactionType::Dispute);
-        assert!(exchange.handle(tx).is_ok());
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
         let tx = Transaction::new(1, 1, TransactionType::Chargeback);
-        assert!(exchange.handle(tx).is_ok());
+        assert!(exchange.handle(&tx).is_ok());
 
         let client = exchange.get_client(1).unwrap();
         assert_eq!(client.total, Amount::new(0, 0));
         assert_eq!(client.available, Amount::new(0, 0));
         assert_eq!(client.held, Amount::new(0, 0));
-        assert_eq!(client.locked, true);
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn test_deposit_overflow() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::MAX));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(2, 1, TransactionType::Deposit(Amount::MAX));
+        assert_eq!(exchange.handle(&tx), Err(ExchangeError::Overflow(tx)));
+
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.total, Amount::MAX);
+        assert_eq!(client.available, Amount::MAX);
+    }
+
+    /// Snapshot of the complete exchange state to check for side effects
+    fn state(exchange: &Exchange) -> (Vec<Client>, Vec<Transaction>) {
+        let mut clients: Vec<Client> = exchange.clients().copied().collect();
+        clients.sort_by_key(|client| client.id);
+        let mut transactions: Vec<Transaction> = exchange.transactions.values().copied().collect();
+        transactions.sort_by_key(|transaction| transaction.tx);
+        (clients, transactions)
+    }
+
+    #[test]
+    fn test_failed_deposit_has_no_side_effects() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::MAX));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        let tx = Transaction::new(2, 1, TransactionType::Deposit(Amount::MAX));
+        assert!(exchange.handle(&tx).is_err());
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_failed_withdraw_has_no_side_effects() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        let tx = Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(500, 0)));
+        assert!(exchange.handle(&tx).is_err());
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_failed_withdraw_keeps_id_available() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Withdraw(Amount::new(500, 0)));
+        assert!(matches!(
+            exchange.handle(&tx),
+            Err(ExchangeError::InsufficientFunds { .. })
+        ));
+        assert!(!exchange.transactions.contains_key(&1));
+
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(100, 0));
+    }
+
+    #[test]
+    fn test_failed_dispute_has_no_side_effects() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::MAX));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(2, 1, TransactionType::Withdraw(Amount::MAX));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(3, 1, TransactionType::Deposit(Amount::MAX));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        // Lowering available succeeds, but raising held overflows
+        let tx = Transaction::new(2, 1, TransactionType::Dispute(None));
+        assert_eq!(exchange.handle(&tx), Err(ExchangeError::Overflow(tx)));
+        assert_eq!(state(&exchange), before);
+
+        // Unknown transaction
+        let tx = Transaction::new(4, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_err());
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_failed_resolve_has_no_side_effects() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        let tx = Transaction::new(2, 1, TransactionType::Resolve(None));
+        assert!(exchange.handle(&tx).is_err());
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_failed_chargeback_has_no_side_effects() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Chargeback);
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        // The client is locked now
+        let tx = Transaction::new(1, 1, TransactionType::Chargeback);
+        assert!(exchange.handle(&tx).is_err());
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_verify_invariants() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(2, 2, TransactionType::Deposit(Amount::new(500, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        assert!(exchange.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_verify_invariants_broken_total() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+
+        let broken = Client {
+            total: Balance::new(1, 0),
+            ..Client::new(2)
+        };
+        exchange.registry.register(broken);
+        assert!(matches!(
+            exchange.verify_invariants(),
+            Err(ExchangeError::InvariantViolation(client, _)) if client == broken
+        ));
+    }
+
+    #[test]
+    fn test_verify_invariants_negative_available() {
+        let mut exchange = Exchange::with_config(
+            ExchangeConfig::default().with_negative_balance(NegativeBalancePolicy::Reject),
+        );
+        let broken = Client {
+            available: Balance::new(-10, 0),
+            held: Balance::new(10, 0),
+            ..Client::new(1)
+        };
+        exchange.registry.register(broken);
+        assert!(matches!(
+            exchange.verify_invariants(),
+            Err(ExchangeError::InvariantViolation(client, _)) if client == broken
+        ));
+    }
+
+    #[test]
+    fn test_apply_rejection_reasons() {
+        let mut exchange = Exchange::new();
+        let rejections = exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::MAX)),
+            // Duplicate ID
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1, 0))),
+            // Overflow
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::MAX)),
+            // Insufficient funds
+            Transaction::new(3, 2, TransactionType::Withdraw(Amount::new(1, 0))),
+            // Unknown transaction
+            Transaction::new(4, 2, TransactionType::Dispute(None)),
+            Transaction::new(5, 3, TransactionType::Deposit(Amount::new(1, 0))),
+            Transaction::new(5, 3, TransactionType::Dispute(None)),
+            Transaction::new(5, 3, TransactionType::Chargeback),
+            // Locked
+            Transaction::new(6, 3, TransactionType::Deposit(Amount::new(1, 0))),
+        ]);
+
+        let reasons: Vec<(TransactionID, ErrorKind)> = rejections
+            .iter()
+            .map(|rejection| (rejection.transaction.tx, rejection.reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (1, ErrorKind::DuplicateId),
+                (2, ErrorKind::Overflow),
+                (3, ErrorKind::InsufficientFunds),
+                (4, ErrorKind::UnknownTransaction),
+                (6, ErrorKind::Locked),
+            ]
+        );
+        assert!(rejections
+            .iter()
+            .all(|rejection| !rejection.message.is_empty()));
+    }
+
+    #[test]
+    fn test_get_client_shared() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+
+        // Several simultaneous reads through a shared reference
+        let exchange = &exchange;
+        let first = exchange.get_client(1).unwrap();
+        let second = exchange.get_client(1).unwrap();
+        assert_eq!(first, second);
+        assert!(exchange.get_client(2).is_none());
+    }
+
+    #[test]
+    fn test_handle_all() {
+        let mut exchange = Exchange::new();
+        let duplicate = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(5, 0)));
+        let overdraw = Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(500, 0)));
+        let unknown = Transaction::new(9, 2, TransactionType::Resolve(None));
+        let failures = exchange.handle_all(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            duplicate,
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(50, 0))),
+            overdraw,
+            unknown,
+            Transaction::new(4, 2, TransactionType::Deposit(Amount::new(10, 0))),
+        ]);
+
+        let rejected: Vec<Transaction> = failures.iter().map(|(tx, _)| *tx).collect();
+        assert_eq!(rejected, vec![duplicate, overdraw, unknown]);
+        assert_eq!(failures[0].1.kind(), ErrorKind::DuplicateId);
+        assert_eq!(failures[1].1.kind(), ErrorKind::InsufficientFunds);
+        assert_eq!(failures[2].1.kind(), ErrorKind::UnknownTransaction);
+
+        // Valid transactions were committed
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(50, 0));
+        assert_eq!(exchange.get_client(2).unwrap().total, Amount::new(10, 0));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(12_345, 4))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(1000, 0))),
+            Transaction::new(3, 2, TransactionType::Withdraw(Amount::new(1, 1))),
+            Transaction::new(2, 2, TransactionType::Dispute(None)),
+            Transaction::new(4, 3, TransactionType::Deposit(Amount::new(5, 0))),
+            Transaction::new(4, 3, TransactionType::Dispute(None)),
+            Transaction::new(4, 3, TransactionType::Chargeback),
+        ]);
+        // Balances beyond the output precision, e.g. from custom transactions
+        let precise = Balance::new(123_456_789, 8);
+        exchange.registry.register(Client {
+            available: precise,
+            total: precise,
+            ..Client::new(5)
+        });
+
+        let snapshot = serde_json::to_string(&exchange).unwrap();
+        let mut restored: Exchange = serde_json::from_str(&snapshot).unwrap();
+        assert_eq!(state(&restored), state(&exchange));
+        // Balances round-trip exactly
+        assert_eq!(restored.get_client(5).unwrap().total, precise);
+
+        // Both behave identically afterwards
+        for tx in [
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1, 0))),
+            Transaction::new(2, 2, TransactionType::Resolve(None)),
+            Transaction::new(5, 3, TransactionType::Deposit(Amount::new(1, 0))),
+            Transaction::new(6, 1, TransactionType::Withdraw(Amount::new(1, 0))),
+        ] {
+            assert_eq!(restored.handle(&tx), exchange.handle(&tx));
+        }
+        assert_eq!(state(&restored), state(&exchange));
+    }
+
+    /// Store that records all calls made to it
+    #[derive(Debug, Default)]
+    struct RecordingStore {
+        transactions: HashMap<TransactionID, Transaction>,
+        calls: RefCell<Vec<(&'static str, TransactionID)>>,
+    }
+
+    impl TransactionStore for RecordingStore {
+        fn insert(&mut self, transaction: Transaction) {
+            self.calls.borrow_mut().push(("insert", transaction.tx));
+            self.transactions.insert(transaction.tx, transaction);
+        }
+
+        fn get(&self, id: TransactionID) -> Option<Transaction> {
+            self.calls.borrow_mut().push(("get", id));
+            self.transactions.get(&id).copied()
+        }
+
+        fn contains(&self, id: TransactionID) -> bool {
+            self.calls.borrow_mut().push(("contains", id));
+            self.transactions.contains_key(&id)
+        }
+
+        fn remove(&mut self, id: TransactionID) -> Option<Transaction> {
+            self.calls.borrow_mut().push(("remove", id));
+            self.transactions.remove(&id)
+        }
+
+        fn len(&self) -> usize {
+            self.transactions.len()
+        }
+    }
+
+    #[test]
+    fn test_custom_store() {
+        let mut exchange = Exchange::with_store(RecordingStore::default());
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        assert!(exchange.handle(&tx).is_err());
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(2, 1, TransactionType::Resolve(None));
+        assert!(exchange.handle(&tx).is_err());
+        assert_eq!(exchange.get_client(1).unwrap().held, Amount::new(1000, 0));
+
+        assert_eq!(
+            exchange.transactions.calls.into_inner(),
+            vec![
+                ("contains", 1),
+                ("insert", 1),
+                ("contains", 1),
+                ("get", 1),
+                ("get", 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transfer() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(
+            2,
+            1,
+            TransactionType::Transfer {
+                to: 2,
+                amount: Amount::new(40, 0),
+            },
+        );
+        assert!(exchange.handle(&tx).is_ok());
+
+        let source = exchange.get_client(1).unwrap();
+        assert_eq!(source.total, Amount::new(60, 0));
+        assert_eq!(source.available, Amount::new(60, 0));
+        let destination = exchange.get_client(2).unwrap();
+        assert_eq!(destination.total, Amount::new(40, 0));
+        assert_eq!(destination.available, Amount::new(40, 0));
+    }
+
+    #[test]
+    fn test_transfer_insufficient_funds() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(2, 2, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        let tx = Transaction::new(
+            3,
+            1,
+            TransactionType::Transfer {
+                to: 2,
+                amount: Amount::new(400, 0),
+            },
+        );
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::InsufficientFunds
+        );
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_transfer_to_locked_client() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 2, TransactionType::Dispute(None)),
+            Transaction::new(2, 2, TransactionType::Chargeback),
+        ]);
+        let before = state(&exchange);
+
+        let tx = Transaction::new(
+            3,
+            1,
+            TransactionType::Transfer {
+                to: 2,
+                amount: Amount::new(50, 0),
+            },
+        );
+        assert!(matches!(
+            exchange.handle(&tx),
+            Err(ExchangeError::Locked(client)) if client.id == 2
+        ));
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_dispute_twice() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::AlreadyDisputed
+        );
+        assert_eq!(exchange.get_client(1).unwrap().held, Amount::new(1000, 0));
+    }
+
+    #[test]
+    fn test_resolve_without_dispute() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Resolve(None));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::NotDisputed
+        );
+        let tx = Transaction::new(1, 1, TransactionType::Chargeback);
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::NotDisputed
+        );
+    }
+
+    #[test]
+    fn test_partial_resolves() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1000, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+
+        let tx = Transaction::new(1, 1, TransactionType::Resolve(Some(Amount::new(300, 0))));
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(exchange.get_client(1).unwrap().held, Amount::new(700, 0));
+        assert_eq!(exchange.disputes.get(&1), Some(&Amount::new(700, 0)));
+
+        // Can't resolve more than is held
+        let tx = Transaction::new(1, 1, TransactionType::Resolve(Some(Amount::new(701, 0))));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::ExceedsDisputed
+        );
+
+        let tx = Transaction::new(1, 1, TransactionType::Resolve(Some(Amount::new(700, 0))));
+        assert!(exchange.handle(&tx).is_ok());
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.held, Amount::new(0, 0));
+        assert_eq!(client.available, Amount::new(1000, 0));
+        // The dispute is closed exactly when nothing is held anymore
+        assert!(exchange.disputes.is_empty());
+        let tx = Transaction::new(1, 1, TransactionType::Resolve(None));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::NotDisputed
+        );
+    }
+
+    fn rebate(client: &mut Client, amount: Option<Amount>) -> Result<(), ExchangeError> {
+        let amount = amount.ok_or_else(|| {
+            ExchangeError::InvalidAmount(String::new(), "A rebate requires an amount".to_string())
+        })?;
+        client.available += amount;
+        client.total += amount;
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_handler() {
+        let mut exchange = Exchange::new();
+        let name: CustomType = "rebate".parse().unwrap();
+        exchange.register_handler(name, rebate);
+
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(2, 1, TransactionType::Custom(name, Some(Amount::new(5, 0))));
+        assert!(exchange.handle(&tx).is_ok());
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Amount::new(105, 0));
+        assert_eq!(client.total, Amount::new(105, 0));
+
+        // Transaction IDs of custom transactions are unique as well
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::DuplicateId
+        );
+    }
+
+    #[test]
+    fn test_negative_available_is_consistent_by_default() {
+        let mut exchange = Exchange::new();
+        let name: CustomType = "bonus".parse().unwrap();
+        exchange.register_handler(name, |client, amount| {
+            let amount = amount.unwrap_or_default();
+            client.available += amount;
+            client.total += amount;
+            Ok(())
+        });
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(5, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+        ]);
+        assert_eq!(
+            exchange.get_client(1).unwrap().available,
+            Balance::new(-5, 0)
+        );
+        assert_eq!(exchange.verify_invariants(), Ok(()));
+
+        let tx = Transaction::new(3, 1, TransactionType::Custom(name, Some(Amount::new(1, 0))));
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.available, Balance::new(-4, 0));
+    }
+
+    #[test]
+    fn test_custom_handler_changing_identity() {
+        let mut exchange = Exchange::new();
+        let deposit = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&deposit).is_ok());
+        let rename: CustomType = "rename".parse().unwrap();
+        exchange.register_handler(rename, |client, _| {
+            client.id = 7;
+            Ok(())
+        });
+        let lock: CustomType = "lock".parse().unwrap();
+        exchange.register_handler(lock, |client, _| {
+            client.locked = true;
+            Ok(())
+        });
+        let before = state(&exchange);
+
+        let tx = Transaction::new(2, 1, TransactionType::Custom(rename, None));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::InvariantViolation
+        );
+        assert!(exchange.get_client(7).is_none());
+        assert_eq!(state(&exchange), before);
+
+        // Locks are only changed through chargebacks and the operator API
+        let tx = Transaction::new(3, 1, TransactionType::Custom(lock, None));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::InvariantViolation
+        );
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_custom_without_handler() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(
+            1,
+            1,
+            TransactionType::Custom("fee".parse().unwrap(), Some(Amount::new(5, 0))),
+        );
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::UnsupportedType
+        );
+    }
+
+    #[test]
+    fn test_custom_handler_breaking_invariants() {
+        let mut exchange = Exchange::new();
+        let name: CustomType = "bonus".parse().unwrap();
+        exchange.register_handler(name, |client, amount| {
+            client.available += amount.unwrap_or_default();
+            Ok(())
+        });
+        let tx = Transaction::new(1, 1, TransactionType::Custom(name, Some(Amount::new(5, 0))));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::InvariantViolation
+        );
+        assert_eq!(exchange.get_client(1).unwrap().available, Amount::new(0, 0));
+    }
+
+    #[test]
+    fn test_unlock_client() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(2, 1, TransactionType::Dispute(None)),
+            Transaction::new(2, 1, TransactionType::Chargeback),
+        ]);
+        let tx = Transaction::new(3, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert_eq!(exchange.handle(&tx).unwrap_err().kind(), ErrorKind::Locked);
+
+        assert!(exchange.unlock_client(1).is_ok());
+        assert!(!exchange.get_client(1).unwrap().locked);
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(110, 0));
+    }
+
+    #[test]
+    fn test_lock_client() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+
+        assert!(exchange.lock_client(1).is_ok());
+        assert!(exchange.get_client(1).unwrap().locked);
+        let tx = Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(10, 0)));
+        assert_eq!(exchange.handle(&tx).unwrap_err().kind(), ErrorKind::Locked);
+    }
+
+    #[test]
+    fn test_lock_unknown_client() {
+        let mut exchange = Exchange::new();
+        assert_eq!(
+            exchange.lock_client(1),
+            Err(ExchangeError::UnknownClient(1))
+        );
+        assert_eq!(
+            exchange.unlock_client(1),
+            Err(ExchangeError::UnknownClient(1))
+        );
+        assert!(exchange.get_client(1).is_none());
+    }
+
+    #[test]
+    fn test_client_transactions() {
+        let mut exchange = Exchange::new();
+        let transactions = vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(5, 1, TransactionType::Withdraw(Amount::new(20, 0))),
+            Transaction::new(3, 2, TransactionType::Withdraw(Amount::new(10, 0))),
+            Transaction::new(4, 1, TransactionType::Deposit(Amount::new(1, 0))),
+        ];
+        exchange.apply(transactions.clone());
+        // Neither rejected transactions nor disputes are part of the history
+        exchange.apply(vec![
+            Transaction::new(6, 1, TransactionType::Withdraw(Amount::new(1000, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+        ]);
+
+        let history: Vec<Transaction> = exchange.client_transactions(1).collect();
+        assert_eq!(
+            history,
+            vec![transactions[0], transactions[2], transactions[4]]
+        );
+        let history: Vec<Transaction> = exchange.client_transactions(2).collect();
+        assert_eq!(history, vec![transactions[1], transactions[3]]);
+        assert_eq!(exchange.client_transactions(3).count(), 0);
+    }
+
+    #[test]
+    fn test_max_total_liabilities() {
+        let mut exchange = Exchange::with_config(ExchangeConfig {
+            max_total_liabilities: Some(Amount::new(1000, 0)),
+            ..ExchangeConfig::default()
+        });
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(600, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(2, 2, TransactionType::Deposit(Amount::new(400, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(exchange.liabilities, Amount::new(1000, 0));
+
+        let tx = Transaction::new(3, 2, TransactionType::Deposit(Amount::new(1, 2)));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::LiabilityLimit
+        );
+        assert_eq!(exchange.liabilities, Amount::new(1000, 0));
+    }
+
+    #[test]
+    fn test_liabilities_running_total() {
+        let mut exchange = Exchange::with_config(ExchangeConfig {
+            max_total_liabilities: Some(Amount::new(1000, 0)),
+            ..ExchangeConfig::default()
+        });
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(600, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(400, 0))),
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Dispute(None)),
+            Transaction::new(2, 2, TransactionType::Chargeback),
+        ]);
+        assert_eq!(exchange.liabilities, Amount::new(500, 0));
+        let sum: Balance = exchange.clients().map(|client| client.total).sum();
+        assert_eq!(exchange.liabilities, sum);
+
+        // The freed up room can be used by deposits again
+        let tx = Transaction::new(4, 1, TransactionType::Deposit(Amount::new(500, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(5, 3, TransactionType::Deposit(Amount::new(1, 0)));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::LiabilityLimit
+        );
+    }
+
+    #[test]
+    fn test_ledger_range() {
+        let mut exchange = Exchange::new();
+        for tx in [5, 1, 4, 2, 3] {
+            let deposit = Transaction::new(tx, 1, TransactionType::Deposit(Amount::new(1, 0)));
+            assert!(exchange.handle(&deposit).is_ok());
+        }
+        // Disputes are not part of the ledger
+        let dispute = Transaction::new(3, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&dispute).is_ok());
+
+        let ids = |range: Vec<&Transaction>| range.iter().map(|t| t.tx).collect::<Vec<_>>();
+        assert_eq!(ids(exchange.ledger_range(2..=4).collect()), vec![2, 3, 4]);
+        assert_eq!(ids(exchange.ledger_range(4..).collect()), vec![4, 5]);
+        assert_eq!(
+            ids(exchange.ledger_range(..).collect()),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert_eq!(exchange.ledger_range(6..).count(), 0);
+    }
+
+    #[test]
+    fn test_invariant_violation_not_committed() {
+        let mut exchange = Exchange::with_config(ExchangeConfig {
+            check_invariants: true,
+            ..ExchangeConfig::default()
+        });
+        let broken = Client {
+            available: Balance::new(10, 0),
+            ..Client::new(1)
+        };
+        exchange.registry.register(broken);
+        let before = state(&exchange);
+
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(5, 0)));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::InvariantViolation
+        );
+        assert_eq!(state(&exchange), before);
+
+        // Transfers to the client are rejected as well
+        let tx = Transaction::new(2, 2, TransactionType::Deposit(Amount::new(5, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(
+            3,
+            2,
+            TransactionType::Transfer {
+                to: 1,
+                amount: Amount::new(1, 0),
+            },
+        );
+        assert!(matches!(
+            exchange.handle(&tx),
+            Err(ExchangeError::InvariantViolation(client, _)) if client.id == 1
+        ));
+    }
+
+    #[test]
+    fn test_dispute_other_clients_transaction() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(2, 2, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        let tx = Transaction::new(1, 2, TransactionType::Dispute(None));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::ClientMismatch
+        );
+        assert_eq!(state(&exchange), before);
+
+        // Resolves and chargebacks are checked as well
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+        for ttype in [TransactionType::Resolve(None), TransactionType::Chargeback] {
+            let tx = Transaction::new(1, 2, ttype);
+            assert_eq!(
+                exchange.handle(&tx).unwrap_err().kind(),
+                ErrorKind::ClientMismatch
+            );
+        }
+        assert_eq!(exchange.get_client(1).unwrap().held, Amount::new(100, 0));
+    }
+
+    #[test]
+    fn test_handle_returns_client() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.total, Amount::new(100, 0));
+        assert_eq!(Some(&client), exchange.get_client(1));
+
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.available, Amount::ZERO);
+        assert_eq!(client.held, Amount::new(100, 0));
+        assert_eq!(Some(&client), exchange.get_client(1));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        // Valid transactions are not applied
+        let deposit = Transaction::new(2, 1, TransactionType::Deposit(Amount::new(5, 0)));
+        assert_eq!(exchange.validate(&deposit), Ok(()));
+        let dispute = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert_eq!(exchange.validate(&dispute), Ok(()));
+        assert_eq!(state(&exchange), before);
+
+        let withdraw = Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(500, 0)));
+        let duplicate = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(5, 0)));
+        let unknown = Transaction::new(2, 3, TransactionType::Withdraw(Amount::new(1, 0)));
+        for tx in [withdraw, duplicate, unknown] {
+            let result = exchange.validate(&tx);
+            assert!(result.is_err());
+            assert_eq!(state(&exchange), before);
+            assert_eq!(exchange.get_client(3), None);
+            assert_eq!(exchange.handle(&tx).map(|_| ()), result);
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(500, 0))),
+            Transaction::new(3, 2, TransactionType::Withdraw(Amount::new(1, 0))),
+        ]);
+        let mut other = Exchange::new();
+        other.apply(vec![
+            Transaction::new(4, 2, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(4, 2, TransactionType::Dispute(None)),
+            Transaction::new(5, 3, TransactionType::Deposit(Amount::new(10, 0))),
+        ]);
+        other.lock_client(2).unwrap();
+
+        assert_eq!(exchange.merge(other), Ok(()));
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(100, 0));
+        let client = exchange.get_client(2).unwrap();
+        assert_eq!(client.held, Amount::new(50, 0));
+        assert!(client.locked);
+        assert_eq!(exchange.get_client(3).unwrap().total, Amount::new(10, 0));
+        assert_eq!(exchange.ledger_range(..).count(), 3);
+        assert_eq!(exchange.client_transactions(2).count(), 1);
+
+        // Disputes carry over
+        exchange.unlock_client(2).unwrap();
+        let tx = Transaction::new(4, 2, TransactionType::Resolve(None));
+        assert!(exchange.handle(&tx).is_ok());
+        assert!(exchange.verify_invariants().is_ok());
+    }
+
+    #[test]
+    fn test_merge_conflict() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        let mut other = Exchange::new();
+        other.apply(vec![
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(1, 3, TransactionType::Deposit(Amount::new(50, 0))),
+        ]);
+        assert_eq!(
+            exchange.merge(other).unwrap_err().kind(),
+            ErrorKind::DuplicateId
+        );
+        assert_eq!(state(&exchange), before);
+        assert_eq!(exchange.get_client(2), None);
+
+        let mut other = Exchange::new();
+        let tx = Transaction::new(2, 1, TransactionType::Deposit(Amount::new(50, 0)));
+        assert!(other.handle(&tx).is_ok());
+        assert_eq!(exchange.merge(other), Err(ExchangeError::ClientConflict(1)));
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_merge_adjusted_client() {
+        let mut exchange = Exchange::new();
+        exchange
+            .apply_adjustment(1, Balance::new(10, 0), "Goodwill".to_string())
+            .unwrap();
+        let before = state(&exchange);
+
+        // Adjustments count as activity of the client
+        let mut other = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(50, 0)));
+        assert!(other.handle(&tx).is_ok());
+        assert_eq!(exchange.merge(other), Err(ExchangeError::ClientConflict(1)));
+        assert_eq!(state(&exchange), before);
+
+        // The adjusted client is kept over an empty one
+        let mut other = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Withdraw(Amount::new(50, 0)));
+        assert!(other.handle(&tx).is_err());
+        exchange.merge(other).unwrap();
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(10, 0));
+    }
+
+    #[test]
+    fn test_withdrawal_fee_percentage() {
+        let mut exchange = Exchange::with_config(ExchangeConfig {
+            withdrawal_fee: Some(WithdrawalFee {
+                percentage: Amount::new(15, 1),
+                ..WithdrawalFee::default()
+            }),
+            ..ExchangeConfig::default()
+        });
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(50, 0))),
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(10, 0))),
+        ]);
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Balance::new(391, 1));
+        assert_eq!(client.total, Balance::new(391, 1));
+        assert_eq!(exchange.collected_fees(), Amount::new(9, 1));
+    }
+
+    #[test]
+    fn test_withdrawal_fee_fixed() {
+        let mut exchange = Exchange::with_config(ExchangeConfig {
+            withdrawal_fee: Some(WithdrawalFee {
+                fixed: Amount::new(2, 0),
+                ..WithdrawalFee::default()
+            }),
+            ..ExchangeConfig::default()
+        });
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(50, 0))),
+            Transaction::new(3, 1, TransactionType::Deposit(Amount::new(10, 0))),
+        ]);
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Balance::new(58, 0));
+        assert_eq!(exchange.collected_fees(), Amount::new(2, 0));
+
+        // Deposits are free, and so is disputing a withdrawal
+        let tx = Transaction::new(2, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.held, Balance::new(50, 0));
+        assert_eq!(exchange.collected_fees(), Amount::new(2, 0));
+    }
+
+    #[test]
+    fn test_withdrawal_fee_insufficient_funds() {
+        let mut exchange = Exchange::with_config(ExchangeConfig {
+            withdrawal_fee: Some(WithdrawalFee {
+                fixed: Amount::new(1, 0),
+                percentage: Amount::new(1, 0),
+            }),
+            ..ExchangeConfig::default()
+        });
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        // Available covers the withdrawal but not the fee of 1.99
+        let tx = Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(99, 0)));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::InsufficientFunds
+        );
+        assert_eq!(state(&exchange), before);
+        assert_eq!(exchange.collected_fees(), Amount::ZERO);
+
+        let tx = Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(98, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(
+            exchange.get_client(1).unwrap().available,
+            Balance::new(2, 2)
+        );
+        assert_eq!(exchange.collected_fees(), Amount::new(198, 2));
+    }
+
+    #[test]
+    fn test_register_client() {
+        let mut exchange = Exchange::new();
+        assert_eq!(exchange.client_count(), 0);
+        assert_eq!(exchange.register_client(1), Ok(&Client::new(1)));
+        assert_eq!(exchange.client_count(), 1);
+        assert_eq!(exchange.get_client(1), Some(&Client::new(1)));
+
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(
+            exchange.register_client(1),
+            Err(ExchangeError::ClientExists(1))
+        );
+        assert_eq!(
+            exchange.get_client(1).unwrap().available,
+            Balance::new(10, 0)
+        );
+
+        let tx = Transaction::new(2, 2, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(exchange.client_count(), 2);
+        assert!(exchange.register_client(3).is_ok());
+        assert_eq!(exchange.client_count(), 3);
+    }
+
+    #[test]
+    fn test_totals() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(255, 1))),
+            Transaction::new(3, 3, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(4, 2, TransactionType::Deposit(Amount::new(5, 0))),
+            Transaction::new(5, 1, TransactionType::Withdraw(Amount::new(20, 0))),
+            Transaction::new(4, 2, TransactionType::Dispute(None)),
+        ]);
+        assert_eq!(exchange.total_liabilities(), Balance::new(1205, 1));
+        assert_eq!(exchange.total_held(), Balance::new(5, 0));
+        assert_eq!(exchange.total_available(), Balance::new(1155, 1));
+        assert_eq!(Exchange::new().total_liabilities(), Balance::ZERO);
+    }
+
+    #[test]
+    fn test_wide_ids() {
+        let store: BTreeMap<u64, Transaction<u64, u64>> = BTreeMap::new();
+        let mut exchange = Exchange::with_store(store);
+        let client = u64::from(ClientID::MAX) + 1;
+        let tx = u64::from(TransactionID::MAX) + 1;
+        let rejected = exchange.apply(vec![
+            Transaction::new(tx, client, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(
+                tx + 1,
+                client + 1,
+                TransactionType::Deposit(Amount::new(50, 0)),
+            ),
+            Transaction::new(
+                tx + 2,
+                client,
+                TransactionType::Transfer {
+                    to: client + 1,
+                    amount: Amount::new(30, 0),
+                },
+            ),
+            Transaction::new(tx, client, TransactionType::Dispute(None)),
+            Transaction::new(tx, client + 1, TransactionType::Dispute(None)),
+        ]);
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].reason, ErrorKind::ClientMismatch);
+
+        // IDs beyond the range of the defaults don't wrap around
+        assert_eq!(exchange.get_client(0), None);
+        let source = exchange.get_client(client).unwrap();
+        assert_eq!(source.available, Balance::new(-30, 0));
+        assert_eq!(source.held, Balance::new(100, 0));
+        let destination = exchange.get_client(client + 1).unwrap();
+        assert_eq!(destination.total, Balance::new(80, 0));
+        assert_eq!(exchange.ledger_range(tx..).count(), 3);
+        assert_eq!(
+            exchange.lock_client(client + 2),
+            Err(ExchangeError::UnknownClient(client + 2))
+        );
+    }
+
+    #[test]
+    fn test_locked_client_rejected_first() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(1, 1, TransactionType::Chargeback),
+        ]);
+        let before = state(&exchange);
+
+        // Locked clients are rejected before anything else is checked, even
+        // the transaction ID
+        for tx in [
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(10, 0))),
+            Transaction::new(3, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(1, 1, TransactionType::Withdraw(Amount::new(10, 0))),
+        ] {
+            assert_eq!(exchange.handle(&tx).unwrap_err().kind(), ErrorKind::Locked);
+        }
+        assert!(!exchange.transactions.contains_key(&2));
+        assert!(!exchange.transactions.contains_key(&3));
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = ExchangeConfig::default().with_max_total_liabilities(Amount::new(100, 0));
+        let mut exchange = Exchange::with_config(config);
+        assert_eq!(exchange.config(), &config);
+        assert_eq!(Exchange::new().config(), &ExchangeConfig::default());
+
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(101, 0)));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::LiabilityLimit
+        );
+        assert!(Exchange::new().handle(&tx).is_ok());
+    }
+
+    #[test]
+    fn test_partial_dispute_resolve() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(Some(Amount::new(30, 0)))),
+        ]);
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Balance::new(70, 0));
+        assert_eq!(client.held, Balance::new(30, 0));
+        assert_eq!(client.total, Balance::new(100, 0));
+
+        let tx = Transaction::new(1, 1, TransactionType::Resolve(None));
+        assert!(exchange.handle(&tx).is_ok());
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Balance::new(100, 0));
+        assert_eq!(client.held, Balance::ZERO);
+    }
+
+    #[test]
+    fn test_partial_dispute_chargeback() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(Some(Amount::new(30, 0)))),
+            Transaction::new(1, 1, TransactionType::Chargeback),
+        ]);
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Balance::new(70, 0));
+        assert_eq!(client.total, Balance::new(70, 0));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn test_partial_dispute_exceeds_transaction() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(Some(Amount::new(101, 0))));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::ExceedsTransaction
+        );
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_close_account() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(100, 0))),
+            Transaction::new(3, 2, TransactionType::Deposit(Amount::new(50, 0))),
+        ]);
+        assert_eq!(
+            exchange.close_account(1),
+            Ok(Client {
+                deposit_count: 1,
+                withdraw_count: 1,
+                deposit_volume: Amount::new(100, 0),
+                withdraw_volume: Amount::new(100, 0),
+                ..Client::new(1)
+            })
+        );
+        assert_eq!(exchange.get_client(1), None);
+        assert_eq!(exchange.client_count(), 1);
+        assert_eq!(
+            exchange.close_account(1),
+            Err(ExchangeError::UnknownClient(1))
+        );
+
+        let client = *exchange.get_client(2).unwrap();
+        assert_eq!(
+            exchange.close_account(2),
+            Err(ExchangeError::NonZeroBalance(client))
+        );
+        assert_eq!(exchange.get_client(2), Some(&client));
+
+        // The IDs of transactions of closed accounts stay taken
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::DuplicateId
+        );
+    }
+
+    #[test]
+    fn test_close_locked_account() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(1, 1, TransactionType::Chargeback),
+        ]);
+        let client = *exchange.get_client(1).unwrap();
+        assert!(client.total.is_zero());
+        assert_eq!(
+            exchange.close_account(1),
+            Err(ExchangeError::Locked(client))
+        );
+        assert_eq!(exchange.get_client(1), Some(&client));
+    }
+
+    #[test]
+    fn test_locked_clients() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(3, 3, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(1, 1, TransactionType::Chargeback),
+            Transaction::new(3, 3, TransactionType::Dispute(None)),
+            Transaction::new(3, 3, TransactionType::Chargeback),
+            Transaction::new(2, 2, TransactionType::Dispute(None)),
+        ]);
+        let mut locked: Vec<ClientID> = exchange.locked_clients().map(|client| client.id).collect();
+        locked.sort_unstable();
+        assert_eq!(locked, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_clients_mut() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(50, 0))),
+        ]);
+        for client in exchange.clients_mut() {
+            client.available += Amount::new(1, 0);
+            client.total += Amount::new(1, 0);
+        }
+        assert_eq!(exchange.get_client(1).unwrap().total, Balance::new(101, 0));
+        assert_eq!(exchange.get_client(2).unwrap().total, Balance::new(51, 0));
+        assert!(exchange.verify_invariants().is_ok());
+
+        // Nothing prevents corrupting a client
+        for client in exchange.clients_mut() {
+            client.total = Balance::ZERO;
+        }
+        assert_eq!(
+            exchange.verify_invariants().unwrap_err().kind(),
+            ErrorKind::InvariantViolation
+        );
+    }
+
+    #[test]
+    fn test_handle_idempotent() {
+        let mut exchange = Exchange::new();
+        let deposit = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        let client = exchange.handle_idempotent(&deposit).unwrap();
+        assert_eq!(client.total, Amount::new(100, 0));
+        let before = state(&exchange);
+
+        // Identical duplicates have no effect
+        assert_eq!(exchange.handle_idempotent(&deposit), Ok(client));
+        assert_eq!(state(&exchange), before);
+        assert_eq!(
+            exchange.handle(&deposit).unwrap_err().kind(),
+            ErrorKind::DuplicateId
+        );
+
+        // Disputes reference a stored transaction, but are no duplicates of it
+        let dispute = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle_idempotent(&dispute).is_ok());
+        assert_eq!(exchange.get_client(1).unwrap().held, Amount::new(100, 0));
+    }
+
+    #[test]
+    fn test_handle_idempotent_partial_withdrawal() {
+        let mut exchange =
+            Exchange::with_config(ExchangeConfig::default().with_partial_withdrawals(true));
+        let deposit = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&deposit).is_ok());
+        let withdraw = Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(50, 0)));
+        let client = exchange.handle_idempotent(&withdraw).unwrap();
+        assert_eq!(client.available, Balance::ZERO);
+        let before = state(&exchange);
+
+        // The retry matches the request, not the stored filled amount
+        assert_eq!(exchange.handle_idempotent(&withdraw), Ok(client));
+        assert_eq!(state(&exchange), before);
+        let filled = Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(10, 0)));
+        assert_eq!(
+            exchange.handle_idempotent(&filled).unwrap_err().kind(),
+            ErrorKind::DuplicateId
+        );
+    }
+
+    #[test]
+    fn test_handle_idempotent_conflict() {
+        let mut exchange = Exchange::new();
+        let deposit = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle_idempotent(&deposit).is_ok());
+        let before = state(&exchange);
+
+        for conflicting in [
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(1, 2, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Withdraw(Amount::new(100, 0))),
+        ] {
+            assert_eq!(
+                exchange.handle_idempotent(&conflicting).unwrap_err().kind(),
+                ErrorKind::DuplicateId
+            );
+        }
+        // Client 2 was created by the rejected transaction
+        assert_eq!(state(&exchange).1, before.1);
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(100, 0));
+    }
+
+    #[test]
+    fn test_observer() {
+        use std::sync::{Arc, Mutex};
+
+        let outcomes = Arc::new(Mutex::new(Vec::new()));
+        let mut exchange = Exchange::new();
+        let observed = Arc::clone(&outcomes);
+        let previous = exchange.set_observer(Box::new(move |transaction, outcome| {
+            observed
+                .lock()
+                .unwrap()
+                .push((transaction.tx, outcome.is_ok()));
+        }));
+        assert!(previous.is_none());
+
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(500, 0))),
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(50, 0))),
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(50, 0))),
+        ]);
+        assert!(exchange
+            .validate(&Transaction::new(
+                4,
+                1,
+                TransactionType::Deposit(Amount::new(1, 0))
+            ))
+            .is_ok());
+        assert_eq!(
+            *outcomes.lock().unwrap(),
+            vec![(1, true), (2, false), (3, true), (3, false)]
+        );
+    }
+
+    #[test]
+    fn test_deposit_chargeback_removes_funds() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(40, 0))),
+            Transaction::new(2, 1, TransactionType::Dispute(None)),
+        ]);
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Amount::new(100, 0));
+        assert_eq!(client.held, Amount::new(40, 0));
+        assert_eq!(client.total, Amount::new(140, 0));
+
+        let tx = Transaction::new(2, 1, TransactionType::Chargeback);
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.available, Amount::new(100, 0));
+        assert_eq!(client.held, Amount::ZERO);
+        assert_eq!(client.total, Amount::new(100, 0));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn test_withdrawal_chargeback_returns_funds() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(40, 0))),
+            Transaction::new(2, 1, TransactionType::Dispute(None)),
+        ]);
+        // The withdrawn funds are held, the remaining funds stay available
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Amount::new(60, 0));
+        assert_eq!(client.held, Amount::new(40, 0));
+        assert_eq!(client.total, Amount::new(100, 0));
+
+        let tx = Transaction::new(2, 1, TransactionType::Chargeback);
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.available, Amount::new(100, 0));
+        assert_eq!(client.held, Amount::ZERO);
+        assert_eq!(client.total, Amount::new(100, 0));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn test_withdrawal_resolve_keeps_withdrawal() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(40, 0))),
+            Transaction::new(2, 1, TransactionType::Dispute(None)),
+        ]);
+        let tx = Transaction::new(2, 1, TransactionType::Resolve(None));
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.available, Amount::new(60, 0));
+        assert_eq!(client.held, Amount::ZERO);
+        assert_eq!(client.total, Amount::new(60, 0));
+        assert!(!client.locked);
+    }
+
+    #[test]
+    fn test_open_disputes() {
+        let mut exchange = Exchange::new();
+        assert_eq!(exchange.open_disputes().count(), 0);
+        assert_eq!(exchange.disputed_total(), Some(Amount::ZERO));
+
+        let deposit = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        let rejected = exchange.apply(vec![
+            deposit,
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(Some(Amount::new(30, 0)))),
+            Transaction::new(2, 2, TransactionType::Dispute(None)),
+            Transaction::new(2, 2, TransactionType::Resolve(None)),
+        ]);
+        assert!(rejected.is_empty());
+
+        assert_eq!(exchange.open_disputes().collect::<Vec<_>>(), vec![deposit]);
+        // Only the disputed part of the deposit counts
+        assert_eq!(exchange.disputed_total(), Some(Amount::new(30, 0)));
+
+        // Each dispute fits, but not their sum
+        exchange.apply(vec![
+            Transaction::new(3, 3, TransactionType::Deposit(Amount::MAX)),
+            Transaction::new(4, 4, TransactionType::Deposit(Amount::MAX)),
+            Transaction::new(3, 3, TransactionType::Dispute(None)),
+            Transaction::new(4, 4, TransactionType::Dispute(None)),
+        ]);
+        assert_eq!(exchange.open_disputes().count(), 3);
+        assert_eq!(exchange.disputed_total(), None);
+    }
+
+    #[test]
+    fn test_negative_balance_policy() {
+        // The deposit gets disputed after most of it was withdrawn
+        let transactions = vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(80, 0))),
+        ];
+        let dispute = Transaction::new(1, 1, TransactionType::Dispute(None));
+        let exchange = |policy| {
+            let config = ExchangeConfig::default().with_negative_balance(policy);
+            let mut exchange = Exchange::with_config(config);
+            exchange.apply(transactions.clone());
+            exchange
+        };
+
+        let mut allow = exchange(NegativeBalancePolicy::Allow);
+        let client = allow.handle(&dispute).unwrap();
+        assert_eq!(client.available, Balance::new(-80, 0));
+        assert_eq!(client.held, Amount::new(100, 0));
+
+        let mut reject = exchange(NegativeBalancePolicy::Reject);
+        let err = reject.handle(&dispute).unwrap_err();
+        assert!(matches!(err, ExchangeError::ArithmeticError(1, _)));
+        assert_eq!(err.kind(), ErrorKind::NegativeBalance);
+        assert_eq!(reject.get_client(1).unwrap().held, Amount::ZERO);
+        assert_eq!(reject.open_disputes().count(), 0);
+
+        let mut clamp = exchange(NegativeBalancePolicy::Clamp);
+        let client = clamp.handle(&dispute).unwrap();
+        assert_eq!(client.available, Amount::ZERO);
+        assert_eq!(client.held, Amount::new(20, 0));
+        assert_eq!(client.total, Amount::new(20, 0));
+        // Only the clamped amount is charged back
+        let client = clamp
+            .handle(&Transaction::new(1, 1, TransactionType::Chargeback))
+            .unwrap();
+        assert_eq!(client.total, Amount::ZERO);
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn test_limit_per_transaction() {
+        let limits = ClientLimits {
+            max_deposit: Some(Amount::new(100, 0)),
+            max_withdrawal: Some(Amount::new(50, 0)),
+            ..ClientLimits::default()
+        };
+        let mut exchange = Exchange::with_config(ExchangeConfig::default().with_limits(limits));
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(101, 0)));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::ClientLimit
+        );
+        let tx = Transaction::new(2, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let tx = Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(60, 0)));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::ClientLimit
+        );
+        let tx = Transaction::new(4, 1, TransactionType::Withdraw(Amount::new(50, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(50, 0));
+    }
+
+    #[test]
+    fn test_cumulative_limit() {
+        let limits = ClientLimits {
+            max_total_deposits: Some(Amount::new(100, 0)),
+            max_total_withdrawals: Some(Amount::new(30, 0)),
+            ..ClientLimits::default()
+        };
+        let mut exchange = Exchange::with_config(ExchangeConfig::default().with_limits(limits));
+        let rejected = exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(60, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(20, 0))),
+            // Rejected transactions don't count towards the limit
+            Transaction::new(3, 1, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(4, 1, TransactionType::Deposit(Amount::new(40, 0))),
+            Transaction::new(5, 1, TransactionType::Withdraw(Amount::new(20, 0))),
+            Transaction::new(6, 1, TransactionType::Withdraw(Amount::new(10, 0))),
+            // Every client has its own limit
+            Transaction::new(7, 2, TransactionType::Deposit(Amount::new(100, 0))),
+        ]);
+        let rejected: Vec<_> = rejected
+            .iter()
+            .map(|rejection| (rejection.transaction.tx, rejection.reason))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![(3, ErrorKind::ClientLimit), (5, ErrorKind::ClientLimit)]
+        );
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(70, 0));
+        assert_eq!(exchange.get_client(2).unwrap().total, Amount::new(100, 0));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 2, TransactionType::Deposit(Amount::new(20, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(10, 0))),
+        ]);
+        let snapshot = exchange.snapshot();
+        assert_eq!(
+            snapshot.iter().map(|client| client.id).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        exchange.apply(vec![
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(5, 0))),
+            Transaction::new(4, 3, TransactionType::Deposit(Amount::new(1, 0))),
+        ]);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0].total, Amount::new(10, 0));
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(5, 0));
+        assert_eq!(exchange.snapshot().len(), 3);
+    }
+
+    #[test]
+    fn test_void_deposit() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(30, 0))),
+        ]);
+        assert!(exchange.void_transaction(2).is_ok());
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Amount::new(100, 0));
+        assert_eq!(client.total, Amount::new(100, 0));
+        assert!(!client.locked);
+        assert_eq!(exchange.client_transactions(1).count(), 1);
+        assert_eq!(
+            exchange.void_transaction(2),
+            Err(ExchangeError::UnknownTransaction(2))
+        );
+        // The ID of a voided transaction can be used again
+        let tx = Transaction::new(2, 1, TransactionType::Deposit(Amount::new(5, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+    }
+
+    #[test]
+    fn test_void_withdrawal() {
+        let fee = WithdrawalFee {
+            fixed: Amount::new(1, 0),
+            ..WithdrawalFee::default()
+        };
+        let mut exchange =
+            Exchange::with_config(ExchangeConfig::default().with_withdrawal_fee(fee));
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(30, 0))),
+        ]);
+        assert!(exchange.void_transaction(2).is_ok());
+        assert_eq!(
+            exchange.get_client(1).unwrap().available,
+            Amount::new(100, 0)
+        );
+        assert_eq!(exchange.collected_fees(), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_void_refunds_charged_fee() {
+        let fee = WithdrawalFee {
+            fixed: Amount::new(1, 0),
+            ..WithdrawalFee::default()
+        };
+        let mut shard = Exchange::with_config(ExchangeConfig::default().with_withdrawal_fee(fee));
+        shard.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(30, 0))),
+        ]);
+
+        // The merged withdrawal was charged a fee this exchange doesn't have
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(3, 2, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(4, 2, TransactionType::Withdraw(Amount::new(5, 0))),
+        ]);
+        assert!(exchange.merge(shard).is_ok());
+        assert_eq!(exchange.collected_fees(), Amount::new(1, 0));
+        assert!(exchange.void_transaction(2).is_ok());
+        assert_eq!(
+            exchange.get_client(1).unwrap().available,
+            Amount::new(100, 0)
+        );
+        assert_eq!(exchange.collected_fees(), Amount::ZERO);
+
+        // A withdrawal that was charged no fee refunds none, even if the
+        // configured fee changed since
+        let mut exchange =
+            Exchange::with_config(ExchangeConfig::default().with_withdrawal_fee(fee));
+        let mut shard = Exchange::new();
+        shard.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(30, 0))),
+        ]);
+        assert!(exchange.merge(shard).is_ok());
+        assert!(exchange.void_transaction(2).is_ok());
+        assert_eq!(
+            exchange.get_client(1).unwrap().available,
+            Amount::new(100, 0)
+        );
+        assert_eq!(exchange.collected_fees(), Amount::ZERO);
+    }
+
+    #[test]
+    fn test_void_disputed() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+        ]);
+        assert_eq!(
+            exchange.void_transaction(1).unwrap_err().kind(),
+            ErrorKind::AlreadyDisputed
+        );
+        exchange
+            .handle(&Transaction::new(1, 1, TransactionType::Chargeback))
+            .unwrap();
+        assert!(matches!(
+            exchange.void_transaction(1),
+            Err(ExchangeError::Locked(_))
+        ));
+    }
+
+    #[test]
+    fn test_void_charged_back() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(1, 1, TransactionType::Chargeback),
+        ]);
+        assert!(exchange.unlock_client(1).is_ok());
+        let before = state(&exchange);
+
+        // The deposit already left the exchange with the chargeback
+        assert_eq!(
+            exchange.void_transaction(1).unwrap_err().kind(),
+            ErrorKind::AlreadyDisputed
+        );
+        assert_eq!(state(&exchange), before);
+        assert_eq!(exchange.get_client(1).unwrap().total, Balance::new(10, 0));
+    }
+
+    #[test]
+    fn test_void_overdraw() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(80, 0))),
+        ]);
+        assert_eq!(
+            exchange.void_transaction(1).unwrap_err().kind(),
+            ErrorKind::InsufficientFunds
+        );
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(20, 0));
+        assert!(exchange.transactions.contains(1));
+    }
+
+    #[test]
+    fn test_held_never_negative() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+        ]);
+        // Corrupt the held funds behind the back of the dispute tracking
+        for client in exchange.clients_mut() {
+            client.held = Balance::new(40, 0);
+            client.available = Balance::new(60, 0);
+        }
+
+        for ttype in [TransactionType::Resolve(None), TransactionType::Chargeback] {
+            let tx = Transaction::new(1, 1, ttype);
+            assert_eq!(
+                exchange.handle(&tx).unwrap_err().kind(),
+                ErrorKind::NegativeBalance
+            );
+            let client = exchange.get_client(1).unwrap();
+            assert_eq!(client.held, Amount::new(40, 0));
+            assert!(!client.locked);
+        }
+    }
+
+    #[test]
+    fn test_adjustment() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+
+        let credit = "Goodwill credit".to_string();
+        assert_eq!(
+            exchange.apply_adjustment(1, Balance::new(15, 0), credit),
+            Ok(())
+        );
+        let debit = "Reverse duplicate credit".to_string();
+        assert_eq!(
+            exchange.apply_adjustment(1, Balance::new(-5, 0), debit),
+            Ok(())
+        );
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Amount::new(110, 0));
+        assert_eq!(client.total, Amount::new(110, 0));
+
+        let notes: Vec<_> = exchange
+            .adjustments()
+            .map(|adjustment| (adjustment.client, adjustment.note.as_str()))
+            .collect();
+        assert_eq!(
+            notes,
+            vec![(1, "Goodwill credit"), (1, "Reverse duplicate credit")]
+        );
+        // Adjustments are not transactions
+        assert_eq!(exchange.client_transactions(1).count(), 1);
+    }
+
+    #[test]
+    fn test_adjustment_overdraw() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+
+        let err = exchange
+            .apply_adjustment(1, Balance::new(-11, 0), "Fee".to_string())
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NegativeBalance);
+        assert_eq!(
+            exchange.get_client(1).unwrap().available,
+            Amount::new(10, 0)
+        );
+        assert_eq!(exchange.adjustments().count(), 0);
+
+        exchange.lock_client(1).unwrap();
+        assert!(matches!(
+            exchange.apply_adjustment(1, Balance::new(1, 0), "Credit".to_string()),
+            Err(ExchangeError::Locked(_))
+        ));
+    }
+
+    #[test]
+    fn test_partial_withdrawal() {
+        let mut exchange = Exchange::with_config(
+            ExchangeConfig::default()
+                .with_withdrawal_fee(WithdrawalFee {
+                    fixed: Amount::new(1, 0),
+                    percentage: Amount::new(1, 0),
+                })
+                .with_partial_withdrawals(true),
+        );
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(102, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+
+        // 100 plus a fee of 2 drains the account
+        let tx = Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(500, 0)));
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.available, Balance::ZERO);
+        assert_eq!(exchange.collected_fees(), Amount::new(2, 0));
+        assert_eq!(
+            exchange.transactions.get(&2).unwrap().ttype,
+            TransactionType::Withdraw(Amount::new(100, 0))
+        );
+
+        // Nothing left to withdraw
+        let tx = Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(1, 0)));
+        let err = exchange.handle(&tx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InsufficientFunds);
+    }
+
+    #[test]
+    fn test_partial_withdrawal_disabled() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+
+        let tx = Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(11, 0)));
+        let err = exchange.handle(&tx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InsufficientFunds);
+        assert_eq!(
+            exchange.get_client(1).unwrap().available,
+            Balance::new(10, 0)
+        );
+        assert!(!exchange.transactions.contains_key(&2));
+    }
+
+    #[test]
+    fn test_hold_and_release() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 1, TransactionType::Hold(Amount::new(4, 0))),
+        ]);
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Balance::new(6, 0));
+        assert_eq!(client.held, Balance::new(4, 0));
+        assert_eq!(client.total, Balance::new(10, 0));
+
+        let tx = Transaction::new(3, 1, TransactionType::Release(Amount::new(3, 0)));
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.available, Balance::new(9, 0));
+        assert_eq!(client.held, Balance::new(1, 0));
+        assert_eq!(client.total, Balance::new(10, 0));
+
+        // Holds are not tied to other transactions and cannot be disputed
+        let tx = Transaction::new(2, 1, TransactionType::Dispute(None));
+        let err = exchange.handle(&tx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotAmountBearing);
+    }
+
+    #[test]
+    fn test_hold_insufficient_funds() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        let tx = Transaction::new(2, 1, TransactionType::Hold(Amount::new(11, 0)));
+        let err = exchange.handle(&tx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InsufficientFunds);
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_release_exceeding_held() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 1, TransactionType::Hold(Amount::new(4, 0))),
+        ]);
+        let before = state(&exchange);
+
+        let tx = Transaction::new(3, 1, TransactionType::Release(Amount::new(5, 0)));
+        let err = exchange.handle(&tx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NegativeBalance);
+        assert_eq!(state(&exchange), before);
+    }
+
+    #[test]
+    fn test_release_keeps_dispute_holds() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+        ]);
+        let before = state(&exchange);
+
+        // The disputed funds are held, but not by an operator
+        let tx = Transaction::new(2, 1, TransactionType::Release(Amount::new(100, 0)));
+        let err = exchange.handle(&tx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NegativeBalance);
+        assert_eq!(state(&exchange), before);
+
+        // Only the funds of the administrative hold can be released
+        let tx = Transaction::new(3, 1, TransactionType::Hold(Amount::new(1, 0)));
+        assert!(exchange.handle(&tx).is_err());
+        exchange.apply(vec![
+            Transaction::new(4, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(5, 1, TransactionType::Hold(Amount::new(10, 0))),
+        ]);
+        let tx = Transaction::new(6, 1, TransactionType::Release(Amount::new(11, 0)));
+        assert!(exchange.handle(&tx).is_err());
+        let tx = Transaction::new(7, 1, TransactionType::Release(Amount::new(10, 0)));
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.available, Balance::new(10, 0));
+        assert_eq!(client.held, Balance::new(100, 0));
+
+        let tx = Transaction::new(1, 1, TransactionType::Chargeback);
+        let client = exchange.handle(&tx).unwrap();
+        assert_eq!(client.held, Balance::ZERO);
+        assert_eq!(client.total, Balance::new(10, 0));
+    }
+
+    #[test]
+    fn test_insufficient_funds_fields() {
+        let mut exchange = Exchange::with_config(ExchangeConfig::default().with_withdrawal_fee(
+            WithdrawalFee {
+                fixed: Amount::new(1, 0),
+                ..WithdrawalFee::default()
+            },
+        ));
+        let tx = Transaction::new(1, 7, TransactionType::Deposit(Amount::new(105, 1)));
+        assert!(exchange.handle(&tx).is_ok());
+
+        let tx = Transaction::new(2, 7, TransactionType::Withdraw(Amount::new(10, 0)));
+        assert_eq!(
+            exchange.handle(&tx),
+            Err(ExchangeError::InsufficientFunds {
+                client: 7,
+                available: Balance::new(105, 1),
+                requested: Amount::new(11, 0),
+            })
+        );
+    }
+
+    #[test]
+    fn test_withdrawal_dispute_policy() {
+        let transactions = vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(4, 0))),
+        ];
+        let dispute = Transaction::new(2, 1, TransactionType::Dispute(None));
+
+        let mut exchange = Exchange::new();
+        exchange.apply(transactions.clone());
+        let client = exchange.handle(&dispute).unwrap();
+        assert_eq!(client.held, Balance::new(4, 0));
+
+        let mut exchange =
+            Exchange::with_config(ExchangeConfig::default().with_withdrawal_disputes(false));
+        exchange.apply(transactions);
+        let before = state(&exchange);
+        let err = exchange.handle(&dispute).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::DisputeNotAllowed);
+        assert_eq!(state(&exchange), before);
+
+        // Deposits can still be disputed
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        assert!(exchange.handle(&tx).is_ok());
+    }
+
+    #[test]
+    fn test_transactions_in_order() {
+        let mut exchange = Exchange::new();
+        let transactions = vec![
+            Transaction::new(5, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(3, 0))),
+            Transaction::new(9, 1, TransactionType::Withdraw(Amount::new(20, 0))),
+            Transaction::new(5, 1, TransactionType::Dispute(None)),
+            Transaction::new(7, 1, TransactionType::Withdraw(Amount::new(1, 0))),
+            Transaction::new(5, 1, TransactionType::Resolve(None)),
+            Transaction::new(1, 2, TransactionType::Withdraw(Amount::new(1, 0))),
+        ];
+        let rejections = exchange.apply(transactions.clone());
+        assert_eq!(rejections.len(), 2);
+
+        // Rejected transactions are not part of the log
+        let accepted: Vec<Transaction> = exchange.transactions_in_order().copied().collect();
+        assert_eq!(
+            accepted,
+            vec![
+                transactions[0],
+                transactions[1],
+                transactions[3],
+                transactions[5],
+                transactions[6],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_activity_counters() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(5, 1))),
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(3, 0))),
+            // Rejected for insufficient funds
+            Transaction::new(4, 1, TransactionType::Withdraw(Amount::new(100, 0))),
+            // Disputes are neither deposits nor withdrawals
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+        ]);
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.deposit_count, 2);
+        assert_eq!(client.deposit_volume, Amount::new(105, 1));
+        assert_eq!(client.withdraw_count, 1);
+        assert_eq!(client.withdraw_volume, Amount::new(3, 0));
+
+        assert!(exchange.void_transaction(3).is_ok());
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.withdraw_count, 0);
+        assert_eq!(client.withdraw_volume, Amount::ZERO);
+    }
+
+    #[test]
+    fn test_evict_settled() {
+        let mut exchange =
+            Exchange::with_config(ExchangeConfig::default().with_max_transactions(2));
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(5, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(1, 1, TransactionType::Resolve(None)),
+        ]);
+        assert_eq!(exchange.transactions.len(), 2);
+
+        // The limit is exceeded, so the settled deposit gets evicted
+        let tx = Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(1, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        assert_eq!(exchange.transactions.len(), 2);
+        assert!(!exchange.transactions.contains_key(&1));
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.total, Balance::new(14, 0));
+        assert_eq!(client.available, Balance::new(14, 0));
+
+        let tx = Transaction::new(1, 1, TransactionType::Dispute(None));
+        let err = exchange.handle(&tx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnknownTransaction);
+    }
+
+    #[test]
+    fn test_evict_settled_manually() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(5, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(2, 1, TransactionType::Dispute(None)),
+            Transaction::new(2, 1, TransactionType::Chargeback),
+        ]);
+        // Transaction 1 is still disputed
+        assert_eq!(exchange.evict_settled(), 1);
+        assert_eq!(exchange.evict_settled(), 0);
+        assert!(exchange.transactions.contains_key(&1));
+        assert!(!exchange.transactions.contains_key(&2));
+        assert_eq!(exchange.get_client(1).unwrap().held, Balance::new(10, 0));
+    }
+
+    #[test]
+    fn test_metrics_snapshot() {
+        let mut exchange = Exchange::new();
+        assert_eq!(exchange.metrics_snapshot(), ExchangeMetrics::default());
+
+        let rejected = exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(3, 3, TransactionType::Deposit(Amount::new(20, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(2, 2, TransactionType::Dispute(None)),
+            Transaction::new(2, 2, TransactionType::Chargeback),
+        ]);
+        assert!(rejected.is_empty());
+
+        let metrics = exchange.metrics_snapshot();
+        assert_eq!(
+            metrics,
+            ExchangeMetrics {
+                clients: 3,
+                locked_clients: 1,
+                transactions: 6,
+                open_disputes: 1,
+                total_liabilities: Balance::new(120, 0),
+            }
+        );
+        let text = metrics.to_string();
+        for name in [
+            "exchange_clients 3",
+            "exchange_locked_clients 1",
+            "exchange_transactions 6",
+            "exchange_open_disputes 1",
+            "exchange_total_liabilities 120",
+        ] {
+            assert!(text.contains(name), "missing {} in {}", name, text);
+        }
+    }
+
+    #[test]
+    fn test_reprocess() {
+        let mut exchange = Exchange::new();
+        let rejected = exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(30, 0))),
+            Transaction::new(3, 2, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(3, 2, TransactionType::Dispute(None)),
+        ]);
+        assert!(rejected.is_empty());
+        let expected = exchange.snapshot();
+
+        let client = exchange
+            .clients_mut()
+            .find(|client| client.id == 1)
+            .unwrap();
+        client.available = Balance::new(1000, 0);
+        client.total = Balance::new(1000, 0);
+        assert_ne!(exchange.snapshot(), expected);
+
+        assert!(exchange.reprocess().is_ok());
+        assert_eq!(exchange.snapshot(), expected);
+        assert_eq!(exchange.open_disputes().count(), 1);
+        assert_eq!(exchange.transactions_in_order().count(), 4);
+        // The stored transactions are still known, so their IDs stay in use
+        let duplicate = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1, 0)));
+        assert!(exchange.handle(&duplicate).is_err());
+    }
+
+    #[test]
+    fn test_reprocess_rejected_transaction() {
+        let mut exchange = Exchange::new();
+        let rejected = exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(1, 1, TransactionType::Chargeback),
+        ]);
+        assert!(rejected.is_empty());
+        assert!(exchange.unlock_client(1).is_ok());
+        let deposit = Transaction::new(2, 1, TransactionType::Deposit(Amount::new(5, 0)));
+        assert!(exchange.handle(&deposit).is_ok());
+
+        let before = exchange.snapshot();
+
+        // The unlock is not replayed, so the deposit is rejected this time
+        assert!(matches!(
+            exchange.reprocess(),
+            Err(ExchangeError::Locked(_))
+        ));
+        // Nothing is lost: the exchange is left as it was
+        assert_eq!(exchange.snapshot(), before);
+        assert!(!exchange.get_client(1).unwrap().locked);
+        assert_eq!(exchange.transactions_in_order().count(), 4);
+        assert_eq!(exchange.client_transactions(1).count(), 2);
+        assert!(exchange.handle(&deposit).is_err());
+    }
+
+    #[test]
+    fn test_reprocess_locks() {
+        let mut exchange = Exchange::new();
+        let rejected = exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(2, 2, TransactionType::Dispute(None)),
+            Transaction::new(2, 2, TransactionType::Chargeback),
+            Transaction::new(3, 3, TransactionType::Deposit(Amount::new(10, 0))),
+        ]);
+        assert!(rejected.is_empty());
+        assert!(exchange.lock_client(1).is_ok());
+        assert!(exchange.unlock_client(2).is_ok());
+        exchange.block_client(3);
+        let expected = exchange.snapshot();
+
+        assert!(exchange.reprocess().is_ok());
+        assert_eq!(exchange.snapshot(), expected);
+        assert!(exchange.get_client(1).unwrap().locked);
+        assert!(!exchange.get_client(2).unwrap().locked);
+    }
+
+    #[test]
+    fn test_reprocess_adjusted() {
+        let mut exchange = Exchange::new();
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        exchange
+            .apply_adjustment(1, Balance::new(10, 0), "Goodwill".to_string())
+            .unwrap();
+        let before = exchange.snapshot();
+
+        assert_eq!(exchange.reprocess(), Err(ExchangeError::Adjusted(1)));
+        assert_eq!(
+            exchange.reprocess().unwrap_err().kind(),
+            ErrorKind::Adjusted
+        );
+        assert_eq!(exchange.snapshot(), before);
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::new(110, 0));
+    }
+
+    #[test]
+    fn test_with_capacity() {
+        let transactions = vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(30, 0))),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(1, 1, TransactionType::Resolve(None)),
+        ];
+        let mut presized = Exchange::<HashMap<_, _>>::with_capacity(16, 1024);
+        assert!(presized.registry.clients.capacity() >= 16);
+        assert!(presized.transactions.capacity() >= 1024);
+        assert!(presized.apply(transactions.clone()).is_empty());
+
+        let mut exchange = Exchange::new();
+        assert!(exchange.apply(transactions).is_empty());
+        assert_eq!(presized.snapshot(), exchange.snapshot());
+
+        // Exceeding the capacity is fine
+        let mut small = Exchange::<HashMap<_, _>>::with_capacity(0, 0);
+        let deposits = (1..=100)
+            .map(|tx| Transaction::new(tx, 1, TransactionType::Deposit(Amount::new(1, 0))));
+        assert!(small.apply(deposits).is_empty());
+        assert_eq!(small.get_client(1).unwrap().total, Balance::new(100, 0));
+    }
+
+    #[test]
+    fn test_block_client() {
+        let mut exchange = Exchange::new();
+        assert!(exchange.block_client(1));
+        assert!(!exchange.block_client(1));
+        assert!(exchange.is_blocked(1));
+
+        let deposit = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        let err = exchange.handle(&deposit).unwrap_err();
+        assert_eq!(err, ExchangeError::Blocked(1));
+        assert_eq!(err.kind(), ErrorKind::Blocked);
+
+        // Transfers to a blocked client are rejected as well
+        let funding = Transaction::new(2, 2, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&funding).is_ok());
+        let transfer = Transaction::new(
+            3,
+            2,
+            TransactionType::Transfer {
+                to: 1,
+                amount: Amount::new(5, 0),
+            },
+        );
+        assert_eq!(exchange.handle(&transfer), Err(ExchangeError::Blocked(1)));
+
+        assert!(exchange.unblock_client(1));
+        assert!(!exchange.unblock_client(1));
+        assert!(!exchange.is_blocked(1));
+        let client = exchange.handle(&deposit).unwrap();
+        assert_eq!(client.total, Balance::new(10, 0));
+        assert!(exchange.handle(&transfer).is_ok());
+    }
+
+    #[test]
+    fn test_withdrawable() {
+        let deposit = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        let withdraw =
+            |tx, amount| Transaction::new(tx, 1, TransactionType::Withdraw(Amount::new(amount, 0)));
+
+        let mut exchange = Exchange::new();
+        assert_eq!(exchange.withdrawable(1), Amount::ZERO);
+        assert!(exchange.handle(&deposit).is_ok());
+        assert_eq!(exchange.withdrawable(1), Amount::new(100, 0));
+        assert!(exchange.handle(&withdraw(2, 100)).is_ok());
+
+        let limits = ClientLimits {
+            min_balance: Some(Amount::new(30, 0)),
+            ..ClientLimits::default()
+        };
+        let config = ExchangeConfig::default().with_limits(limits);
+        let mut exchange = Exchange::with_config(config);
+        assert!(exchange.handle(&deposit).is_ok());
+        assert_eq!(exchange.withdrawable(1), Amount::new(70, 0));
+        // The reserve cannot be withdrawn, although it is available
+        assert_eq!(
+            exchange.handle(&withdraw(2, 71)),
+            Err(ExchangeError::InsufficientFunds {
+                client: 1,
+                available: Balance::new(70, 0),
+                requested: Amount::new(71, 0),
+            })
+        );
+        let client = exchange.handle(&withdraw(3, 70)).unwrap();
+        assert_eq!(client.available, Balance::new(30, 0));
+        assert_eq!(exchange.withdrawable(1), Amount::ZERO);
+
+        // The fee is taken from the withdrawable funds as well
+        let fee = WithdrawalFee {
+            fixed: Amount::new(1, 0),
+            ..WithdrawalFee::default()
+        };
+        let mut exchange = Exchange::with_config(config.with_withdrawal_fee(fee));
+        assert!(exchange.handle(&deposit).is_ok());
+        assert_eq!(exchange.withdrawable(1), Amount::new(69, 0));
+        assert!(exchange.handle(&withdraw(2, 70)).is_err());
+        assert!(exchange.handle(&withdraw(3, 69)).is_ok());
+    }
+
+    #[test]
+    fn test_reserve_applies_to_transfers_and_holds() {
+        let limits = ClientLimits {
+            min_balance: Some(Amount::new(30, 0)),
+            ..ClientLimits::default()
+        };
+        let mut exchange = Exchange::with_config(ExchangeConfig::default().with_limits(limits));
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(exchange.handle(&tx).is_ok());
+        let before = state(&exchange);
+
+        let transfer = |tx, amount| {
+            Transaction::new(
+                tx,
+                1,
+                TransactionType::Transfer {
+                    to: 2,
+                    amount: Amount::new(amount, 0),
+                },
+            )
+        };
+        assert_eq!(
+            exchange.handle(&transfer(2, 71)),
+            Err(ExchangeError::InsufficientFunds {
+                client: 1,
+                available: Balance::new(70, 0),
+                requested: Amount::new(71, 0),
+            })
+        );
+        let tx = Transaction::new(3, 1, TransactionType::Hold(Amount::new(71, 0)));
+        assert_eq!(
+            exchange.handle(&tx).unwrap_err().kind(),
+            ErrorKind::InsufficientFunds
+        );
+        assert_eq!(state(&exchange), before);
+
+        let client = exchange.handle(&transfer(4, 70)).unwrap();
+        assert_eq!(client.available, Balance::new(30, 0));
+        assert_eq!(
+            exchange.get_client(2).unwrap().available,
+            Balance::new(70, 0)
+        );
+    }
+
+    #[test]
+    fn test_reserve_balance() {
+        let fee = WithdrawalFee {
+            fixed: Amount::new(1, 0),
+            percentage: Amount::new(1, 0),
+        };
+        let mut exchange =
+            Exchange::with_config(ExchangeConfig::default().with_withdrawal_fee(fee));
+        assert_eq!(exchange.reserve_balance(), Balance::ZERO);
+        let rejected = exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(50, 0))),
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(40, 0))),
+        ]);
+        assert!(rejected.is_empty());
+
+        // The fee of 1.40 moved from the client to the reserve
+        assert_eq!(exchange.reserve_balance(), Balance::new(14, 1));
+        let deposited = Balance::new(150, 0);
+        let withdrawn = Balance::new(40, 0);
+        assert_eq!(
+            exchange.total_liabilities() + exchange.reserve_balance(),
+            deposited - withdrawn
+        );
+
+        assert_eq!(exchange.sweep_reserve(Amount::new(1, 0)), Amount::new(1, 0));
+        assert_eq!(exchange.reserve_balance(), Balance::new(4, 1));
+        assert_eq!(exchange.sweep_reserve(Amount::new(1, 0)), Amount::new(4, 1));
+        assert_eq!(exchange.reserve_balance(), Balance::ZERO);
+        // The collected fees still count the fees of all time
+        assert_eq!(exchange.collected_fees(), Amount::new(14, 1));
+    }
+
+    #[test]
+    fn test_transfer_credit_failure() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(10, 0))),
+        ]);
+        // Crediting the destination overflows after the source was debited
+        let destination = exchange
+            .clients_mut()
+            .find(|client| client.id == 2)
+            .unwrap();
+        destination.available = Balance::MAX;
+        destination.total = Balance::MAX;
+        let before = state(&exchange);
+
+        let tx = Transaction::new(
+            3,
+            1,
+            TransactionType::Transfer {
+                to: 2,
+                amount: Amount::new(50, 0),
+            },
+        );
+        assert_eq!(exchange.handle(&tx), Err(ExchangeError::Overflow(tx)));
+        assert_eq!(state(&exchange), before);
+        assert_eq!(exchange.get_client(1).unwrap().total, Balance::new(100, 0));
+        assert_eq!(exchange.client_transactions(1).count(), 1);
+    }
+
+    #[test]
+    fn test_into_clients() {
+        let mut exchange = Exchange::new();
+        let rejected = exchange.apply(vec![
+            Transaction::new(1, 3, TransactionType::Deposit(Amount::new(30, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(3, 2, TransactionType::Deposit(Amount::new(20, 0))),
+        ]);
+        assert!(rejected.is_empty());
+        let snapshot = exchange.snapshot();
+
+        let clients = exchange.into_clients();
+        assert_eq!(
+            clients.iter().map(|client| client.id).collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert_eq!(clients[2].total, Balance::new(30, 0));
+        assert_eq!(clients, snapshot);
+    }
+
+    #[test]
+    fn test_dispute_requires_deposit_or_withdrawal() {
+        let mut exchange = Exchange::new();
+        let rejected = exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(
+                2,
+                1,
+                TransactionType::Transfer {
+                    to: 2,
+                    amount: Amount::new(4, 0),
+                },
+            ),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(1, 1, TransactionType::Resolve(None)),
+        ]);
+        assert!(rejected.is_empty());
+        // Disputes and their outcomes are never stored themselves
+        assert_eq!(exchange.transactions.len(), 2);
+
+        let dispute = |tx| Transaction::new(tx, 1, TransactionType::Dispute(None));
+        assert_eq!(
+            exchange.handle(&dispute(2)).unwrap_err().kind(),
+            ErrorKind::NotAmountBearing
+        );
+        // A transaction ID only ever used by disputes doesn't refer to anything
+        assert_eq!(
+            exchange.handle(&dispute(3)).unwrap_err().kind(),
+            ErrorKind::UnknownTransaction
+        );
+        let chargeback = Transaction::new(3, 1, TransactionType::Chargeback);
+        assert_eq!(
+            exchange.handle(&chargeback).unwrap_err().kind(),
+            ErrorKind::UnknownTransaction
+        );
+        assert_eq!(exchange.transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_no_phantom_clients() {
+        let mut exchange = Exchange::new();
+        let deposit = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(exchange.handle(&deposit).is_ok());
+
+        for ttype in [
+            TransactionType::Dispute(None),
+            TransactionType::Resolve(None),
+            TransactionType::Chargeback,
+        ] {
+            assert!(exchange.handle(&Transaction::new(7, 2, ttype)).is_err());
+        }
+        assert!(exchange.get_client(2).is_none());
+        assert_eq!(exchange.client_count(), 1);
+
+        // Other rejected transactions still create their client
+        let withdraw = Transaction::new(8, 3, TransactionType::Withdraw(Amount::new(1, 0)));
+        assert!(exchange.handle(&withdraw).is_err());
+        assert_eq!(exchange.get_client(3), Some(&Client::new(3)));
+
+        // A dispute of a closed account doesn't open it again
+        let withdraw = Transaction::new(9, 1, TransactionType::Withdraw(Amount::new(10, 0)));
+        assert!(exchange.handle(&withdraw).is_ok());
+        assert!(exchange.close_account(1).is_ok());
+        assert_eq!(
+            exchange.handle(&Transaction::new(1, 1, TransactionType::Dispute(None))),
+            Err(ExchangeError::UnknownClient(1))
+        );
+        assert!(exchange.get_client(1).is_none());
     }
 }
@@ -0,0 +1,143 @@
+use std::{fs::File, path::Path, sync::Arc};
+
+use arrow_array::{ArrayRef, BooleanArray, RecordBatch, StringArray, UInt16Array};
+use parquet::{arrow::ArrowWriter, errors::ParquetError};
+
+use crate::{
+    client::PRECISION, Balance, Client, Exchange, ExchangeError, RoundingMode, TransactionStore,
+};
+
+impl<S: TransactionStore> Exchange<S> {
+    /// Export the balances of all clients to a Parquet file, e.g. for loading
+    /// them into a data warehouse.
+    ///
+    /// The file has the same columns as the CSV output: `client`, `available`,
+    /// `held`, `total` and `locked`. Rows are sorted by client ID. Amounts are
+    /// stored as strings, rounded to the output precision with
+    /// `ExchangeConfig::rounding`.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::Io` if the file cannot be written
+    pub fn export_parquet<P: AsRef<Path>>(&self, path: P) -> Result<(), ExchangeError> {
+        let mut clients: Vec<&Client> = self.clients().collect();
+        clients.sort_by_key(|client| client.id);
+        write_parquet(&clients, self.config().rounding, path.as_ref())
+            .map_err(|err| ExchangeError::Io(err.to_string()))
+    }
+}
+
+fn write_parquet(
+    clients: &[&Client],
+    rounding: RoundingMode,
+    path: &Path,
+) -> Result<(), ParquetError> {
+    let amounts = |amount: fn(&Client) -> Balance| -> ArrayRef {
+        Arc::new(StringArray::from_iter_values(clients.iter().map(
+            |client| rounding.round(amount(client), PRECISION).to_string(),
+        )))
+    };
+    let batch = RecordBatch::try_from_iter(vec![
+        (
+            "client",
+            Arc::new(UInt16Array::from_iter_values(
+                clients.iter().map(|client| client.id),
+            )) as ArrayRef,
+        ),
+        ("available", amounts(|client| client.available)),
+        ("held", amounts(|client| client.held)),
+        ("total", amounts(|client| client.total)),
+        (
+            "locked",
+            Arc::new(BooleanArray::from(
+                clients
+                    .iter()
+                    .map(|client| client.locked)
+                    .collect::<Vec<bool>>(),
+            )),
+        ),
+    ])?;
+
+    let mut writer = ArrowWriter::try_new(File::create(path)?, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::UInt16Type;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use super::*;
+    use crate::{Amount, ExchangeConfig, Transaction, TransactionType};
+
+    fn read_parquet(exchange: &Exchange) -> RecordBatch {
+        let path = std::env::temp_dir().join(format!("exchange-{}.parquet", std::process::id()));
+        exchange.export_parquet(&path).unwrap();
+
+        let file = File::open(&path).unwrap();
+        let mut reader = ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap();
+        let batch = reader.next().unwrap().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        batch
+    }
+
+    fn strings<'a>(batch: &'a RecordBatch, column: &str) -> Vec<&'a str> {
+        batch
+            .column_by_name(column)
+            .unwrap()
+            .as_string::<i32>()
+            .iter()
+            .map(Option::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn test_export_parquet() {
+        let mut exchange = Exchange::new();
+        exchange.apply(vec![
+            Transaction::new(1, 2, TransactionType::Deposit(Amount::new(12_346, 4))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Dispute(None)),
+            Transaction::new(2, 1, TransactionType::Chargeback),
+        ]);
+
+        let batch = read_parquet(&exchange);
+
+        assert_eq!(batch.num_rows(), 2);
+        let ids: Vec<u16> = batch
+            .column_by_name("client")
+            .unwrap()
+            .as_primitive::<UInt16Type>()
+            .values()
+            .to_vec();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(strings(&batch, "available"), vec!["0.0000", "1.2346"]);
+        let locked = batch.column_by_name("locked").unwrap().as_boolean();
+        assert!(locked.value(0));
+        assert!(!locked.value(1));
+
+        // Midpoints are rounded as configured
+        for (rounding, expected) in [
+            (RoundingMode::HalfEven, "1.2346"),
+            (RoundingMode::HalfUp, "1.2347"),
+        ] {
+            let mut exchange = Exchange::with_config(ExchangeConfig {
+                rounding,
+                ..ExchangeConfig::default()
+            });
+            let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(1, 0)));
+            assert!(exchange.handle(&tx).is_ok());
+            for client in exchange.clients_mut() {
+                client.available = Balance::new(123_465, 5);
+            }
+            let batch = read_parquet(&exchange);
+            assert_eq!(strings(&batch, "available"), vec![expected]);
+        }
+    }
+}
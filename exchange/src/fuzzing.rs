@@ -0,0 +1,77 @@
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+use crate::{Amount, ClientID, Transaction, TransactionID, TransactionType};
+
+/// Upper bound of generated amounts in units of 0.0001, i.e. 100,000. Sums of
+/// many such amounts stay far away from overflowing.
+const MAX_AMOUNT: i64 = 1_000_000_000;
+/// Upper bound of generated client IDs. Few clients make it likely that
+/// several transactions of a stream affect the same client.
+const MAX_CLIENT: ClientID = 15;
+/// Upper bound of generated transaction IDs. Few IDs make it likely that
+/// disputes, resolves and chargebacks refer to an earlier transaction.
+const MAX_TX: TransactionID = 255;
+
+impl<'a> Arbitrary<'a> for Amount {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Amount::new(u.int_in_range(0..=MAX_AMOUNT)?, 4))
+    }
+}
+
+/// Custom transaction types are never generated, as they require a handler
+impl<'a> Arbitrary<'a> for TransactionType {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let ttype = match u.int_in_range(0..=7)? {
+            0 => TransactionType::Deposit(u.arbitrary()?),
+            1 => TransactionType::Withdraw(u.arbitrary()?),
+            2 => TransactionType::Transfer {
+                to: u.int_in_range(0..=MAX_CLIENT)?,
+                amount: u.arbitrary()?,
+            },
+            3 => TransactionType::Dispute(u.arbitrary()?),
+            4 => TransactionType::Resolve(u.arbitrary()?),
+            5 => TransactionType::Chargeback,
+            6 => TransactionType::Hold(u.arbitrary()?),
+            _ => TransactionType::Release(u.arbitrary()?),
+        };
+        Ok(ttype)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Transaction {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Transaction::new(
+            u.int_in_range(0..=MAX_TX)?,
+            u.int_in_range(0..=MAX_CLIENT)?,
+            u.arbitrary()?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Exchange, ExchangeConfig, NegativeBalancePolicy};
+
+    #[test]
+    fn test_arbitrary_transactions() {
+        // Any fixed bytes will do, as long as the test is deterministic
+        let seed: Vec<u8> = (0..4096_u32)
+            .map(|i| i.wrapping_mul(2_654_435_761).to_le_bytes()[2])
+            .collect();
+        let mut u = Unstructured::new(&seed);
+        let transactions: Vec<Transaction> = std::iter::from_fn(|| u.arbitrary().ok())
+            .take(200)
+            .collect();
+        assert!(transactions.len() > 10);
+
+        // Disputes after withdrawals would otherwise make funds negative
+        let mut exchange = Exchange::with_config(
+            ExchangeConfig::default().with_negative_balance(NegativeBalancePolicy::Reject),
+        );
+        for transaction in transactions {
+            let _ = exchange.handle(&transaction);
+            assert_eq!(exchange.verify_invariants(), Ok(()));
+        }
+    }
+}
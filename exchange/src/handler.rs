@@ -0,0 +1,41 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{Amount, Client, ClientID, CustomType, ExchangeError, TransactionID};
+
+/// Handler applying a custom transaction type to a client.
+///
+/// It receives the client of the transaction and the (optional) amount of the
+/// transaction. Changes only get committed if the handler succeeds and the
+/// client is still consistent afterwards.
+pub type Handler<C = ClientID, T = TransactionID> =
+    Box<dyn Fn(&mut Client<C>, Option<Amount>) -> Result<(), ExchangeError<C, T>> + Send + Sync>;
+
+/// Handlers for all custom transaction types known to an exchange
+pub(crate) struct Handlers<C, T>(HashMap<CustomType, Handler<C, T>>);
+
+impl<C, T> Handlers<C, T> {
+    pub(crate) fn insert(
+        &mut self,
+        name: CustomType,
+        handler: Handler<C, T>,
+    ) -> Option<Handler<C, T>> {
+        self.0.insert(name, handler)
+    }
+
+    pub(crate) fn get(&self, name: CustomType) -> Option<&Handler<C, T>> {
+        self.0.get(&name)
+    }
+}
+
+// Deriving would require `C: Default` and `T: Default`
+impl<C, T> Default for Handlers<C, T> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<C, T> fmt::Debug for Handlers<C, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
+}
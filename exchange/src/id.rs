@@ -0,0 +1,13 @@
+use std::{fmt, hash::Hash};
+
+use num_traits::PrimInt;
+
+/// Integer type of client and transaction IDs.
+///
+/// By default, the exchange uses `ClientID` and `TransactionID`. Deployments
+/// with more clients or transactions can use wider types instead, e.g. `u64`
+/// (see `Exchange::with_store`). This trait is implemented for all primitive
+/// integers.
+pub trait Id: PrimInt + Hash + fmt::Debug + fmt::Display {}
+
+impl<I: PrimInt + Hash + fmt::Debug + fmt::Display> Id for I {}
@@ -0,0 +1,167 @@
+use std::{convert::TryFrom, io};
+
+use crate::{Exchange, ExchangeError, RawTransaction, Transaction, TransactionStore};
+
+/// Create a CSV reader for transactions in the exchange's input format: a
+/// header row followed by one transaction per row with the columns `type`,
+/// `client`, `tx`, `amount` and an optional `to`. Whitespace around values is
+/// ignored, as are lines starting with `#`.
+pub fn csv_reader<R: io::Read>(input: R) -> csv::Reader<R> {
+    csv_reader_builder().from_reader(input)
+}
+
+/// The configuration of `csv_reader`, e.g. for adjusting the handling of
+/// comments or quotes before creating a reader
+#[must_use]
+pub fn csv_reader_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .quoting(false)
+        .delimiter(b',')
+        .double_quote(false)
+        .has_headers(true)
+        .comment(Some(b'#'));
+    builder
+}
+
+/// Columns every input must have. `ttype` is accepted as an alias of `type`.
+const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+/// Columns an input may have in addition to the required ones
+const OPTIONAL_COLUMNS: [&str; 3] = ["amount", "to", "timestamp"];
+
+/// Check the header row of an input against the columns of the input format,
+/// see `csv_reader`. Otherwise a wrong header would only show up as every row
+/// failing to parse.
+///
+/// ## Errors
+///
+/// Returns `ExchangeError::InvalidHeader` naming the missing and unexpected
+/// columns
+pub fn check_headers(headers: &csv::StringRecord) -> Result<(), ExchangeError> {
+    let columns: Vec<&str> = headers
+        .iter()
+        .map(|column| if column == "ttype" { "type" } else { column })
+        .collect();
+    let missing: Vec<&str> = REQUIRED_COLUMNS
+        .iter()
+        .filter(|column| !columns.contains(column))
+        .copied()
+        .collect();
+    let unexpected: Vec<&str> = columns
+        .iter()
+        .filter(|column| !REQUIRED_COLUMNS.contains(column) && !OPTIONAL_COLUMNS.contains(column))
+        .copied()
+        .collect();
+    if missing.is_empty() && unexpected.is_empty() {
+        return Ok(());
+    }
+    let mut problems = Vec::new();
+    if !missing.is_empty() {
+        problems.push(format!("missing columns: {}", missing.join(", ")));
+    }
+    if !unexpected.is_empty() {
+        problems.push(format!("unexpected columns: {}", unexpected.join(", ")));
+    }
+    Err(ExchangeError::InvalidHeader(problems.join("; ")))
+}
+
+impl<S: TransactionStore> Exchange<S> {
+    /// Read transactions from CSV (see `csv_reader` for the format) and handle
+    /// them in order. Rows that cannot be parsed and transactions rejected by
+    /// the exchange are skipped.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::Io` if reading the input fails and
+    /// `ExchangeError::InvalidHeader` if the header row doesn't match the
+    /// input format
+    pub fn process_csv<R: io::Read>(&mut self, input: R) -> Result<(), ExchangeError> {
+        let io_error = |err: csv::Error| ExchangeError::Io(err.to_string());
+        let mut reader = csv_reader(input);
+        check_headers(reader.headers().map_err(io_error)?)?;
+        for row in reader.deserialize::<RawTransaction>() {
+            let raw = match row {
+                Ok(raw) => raw,
+                Err(err) if err.is_io_error() => return Err(io_error(err)),
+                Err(_) => continue,
+            };
+            if let Ok(transaction) = Transaction::try_from(raw) {
+                let _ = self.handle(&transaction);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, Balance};
+
+    #[test]
+    fn test_process_csv() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 10.0\n\
+                     # comment\n\
+                     deposit, 2, 2, 2.5\n\
+                     withdrawal, 1, 3, 1.5\n\
+                     withdrawal, 2, 4, 5.0\n\
+                     deposit, 2, 5, -1.0\n\
+                     unknown, 2, 6, 1.0\n\
+                     dispute, 2, 2,\n";
+        let mut exchange = Exchange::new();
+        assert_eq!(exchange.process_csv(input.as_bytes()), Ok(()));
+
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.available, Amount::new(85, 1));
+        let client = exchange.get_client(2).unwrap();
+        assert_eq!(client.available, Balance::ZERO);
+        assert_eq!(client.held, Amount::new(25, 1));
+        assert_eq!(exchange.clients().count(), 2);
+    }
+
+    #[test]
+    fn test_process_csv_io_error() {
+        struct Failing;
+
+        impl io::Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk on fire"))
+            }
+        }
+
+        let mut exchange = Exchange::new();
+        assert!(matches!(
+            exchange.process_csv(Failing),
+            Err(ExchangeError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_headers() {
+        let headers = |input: &str| csv_reader(input.as_bytes()).headers().unwrap().clone();
+        assert_eq!(
+            check_headers(&headers("type, client, tx, amount\n")),
+            Ok(())
+        );
+        assert_eq!(
+            check_headers(&headers("ttype,client,tx,amount,to\n")),
+            Ok(())
+        );
+        assert_eq!(check_headers(&headers("client,tx,type\n")), Ok(()));
+        assert_eq!(
+            check_headers(&headers("kind,client_id,tx,amount\n")),
+            Err(ExchangeError::InvalidHeader(
+                "missing columns: type, client; unexpected columns: kind, client_id".to_string()
+            ))
+        );
+
+        let mut exchange = Exchange::new();
+        assert!(matches!(
+            exchange.process_csv("kind,client,tx,amount\ndeposit,1,1,1.0\n".as_bytes()),
+            Err(ExchangeError::InvalidHeader(_))
+        ));
+    }
+}
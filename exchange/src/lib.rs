@@ -12,19 +12,67 @@
     variant_size_differences,
     clippy::missing_const_for_fn
 )]
-#![deny(anonymous_parameters, macro_use_extern_crate, pointer_structural_match)]
+#![deny(anonymous_parameters, macro_use_extern_crate)]
 #![deny(missing_docs)]
 
+// Only used by the snapshot tests, which require the `serde` feature
+#[cfg(all(test, not(feature = "serde")))]
+use serde_json as _;
+
+mod adjustment;
 mod amount;
+mod asset;
+#[cfg(feature = "bincode")]
+mod binary;
 mod client;
+mod config;
+#[cfg(feature = "serde")]
+mod conversion;
 mod error;
 mod exchange;
+#[cfg(feature = "parquet")]
+mod export;
+#[cfg(feature = "fuzzing")]
+mod fuzzing;
+mod handler;
+mod id;
+#[cfg(feature = "csv")]
+mod ingest;
+mod lifecycle;
+mod metrics;
+mod observer;
 mod registry;
+mod rejection;
+mod sharded;
+mod store;
+#[cfg(feature = "futures")]
+mod stream;
 mod transaction;
+#[cfg(feature = "wal")]
+mod wal;
 
 pub use crate::exchange::Exchange;
-pub use amount::Amount;
+pub use adjustment::Adjustment;
+pub use amount::{Amount, Balance, PrecisionPolicy};
+pub use asset::{AssetBalance, AssetId, MultiAssetExchange};
+#[cfg(feature = "serde")]
+pub use client::RoundedClient;
 pub use client::{Client, ClientID};
-pub use error::ExchangeError;
+pub use config::{
+    ClientLimits, ExchangeConfig, NegativeBalancePolicy, RoundingMode, WithdrawalFee,
+};
+#[cfg(feature = "serde")]
+pub use conversion::RawTransaction;
+pub use error::{ErrorKind, ExchangeError};
+pub use handler::Handler;
+pub use id::Id;
+#[cfg(feature = "csv")]
+pub use ingest::{check_headers, csv_reader, csv_reader_builder};
+pub use lifecycle::{DisputableTransaction, DisputedTransaction};
+pub use metrics::ExchangeMetrics;
+pub use observer::Observer;
 pub use registry::Registry;
-pub use transaction::{Transaction, TransactionID, TransactionType};
+pub use rejection::RejectionRecord;
+pub use sharded::ShardedExchange;
+pub use store::TransactionStore;
+pub use transaction::{CustomType, Transaction, TransactionID, TransactionKind, TransactionType};
@@ -0,0 +1,131 @@
+use crate::{Amount, ClientID, Transaction, TransactionID, TransactionType};
+
+/// A deposit or withdrawal, which can be disputed.
+///
+/// Together with `DisputedTransaction`, this encodes the lifecycle of a
+/// transaction in the type system: only deposits and withdrawals can be
+/// disputed and only a disputed transaction can be resolved or charged back.
+/// Each state yields the `Transaction` that has to be handled by the exchange
+/// to get into that state.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DisputableTransaction {
+    transaction: Transaction,
+}
+
+impl DisputableTransaction {
+    /// Create a new deposit
+    #[must_use]
+    pub const fn deposit(tx: TransactionID, client: ClientID, amount: Amount) -> Self {
+        Self {
+            transaction: Transaction::new(tx, client, TransactionType::Deposit(amount)),
+        }
+    }
+
+    /// Create a new withdrawal
+    #[must_use]
+    pub const fn withdraw(tx: TransactionID, client: ClientID, amount: Amount) -> Self {
+        Self {
+            transaction: Transaction::new(tx, client, TransactionType::Withdraw(amount)),
+        }
+    }
+
+    /// The deposit or withdrawal to commit to the exchange
+    #[must_use]
+    pub const fn transaction(&self) -> Transaction {
+        self.transaction
+    }
+
+    /// Dispute the transaction
+    #[must_use]
+    pub const fn dispute(self) -> DisputedTransaction {
+        DisputedTransaction {
+            transaction: self.transaction,
+        }
+    }
+}
+
+/// A disputed deposit or withdrawal, which can be resolved or charged back
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DisputedTransaction {
+    transaction: Transaction,
+}
+
+impl DisputedTransaction {
+    /// The dispute to commit to the exchange
+    #[must_use]
+    pub const fn transaction(&self) -> Transaction {
+        self.with_type(TransactionType::Dispute(None))
+    }
+
+    /// Resolve the dispute. Returns the resolve to commit to the exchange.
+    #[must_use]
+    pub const fn resolve(self) -> Transaction {
+        self.with_type(TransactionType::Resolve(None))
+    }
+
+    /// Charge back the disputed transaction. Returns the chargeback to commit
+    /// to the exchange.
+    #[must_use]
+    pub const fn chargeback(self) -> Transaction {
+        self.with_type(TransactionType::Chargeback)
+    }
+
+    /// Transaction of the given type referencing the disputed transaction
+    const fn with_type(&self, ttype: TransactionType) -> Transaction {
+        Transaction::new(self.transaction.tx, self.transaction.client, ttype)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Exchange;
+
+    #[test]
+    fn test_dispute_resolve() {
+        let mut exchange = Exchange::new();
+        let deposit = DisputableTransaction::deposit(1, 1, Amount::new(100, 0));
+        assert!(exchange.handle(&deposit.transaction()).is_ok());
+
+        let dispute = deposit.dispute();
+        assert_eq!(dispute.transaction().ttype, TransactionType::Dispute(None));
+        assert!(exchange.handle(&dispute.transaction()).is_ok());
+        assert_eq!(exchange.get_client(1).unwrap().held, Amount::new(100, 0));
+
+        let resolve = dispute.resolve();
+        assert_eq!(
+            resolve,
+            Transaction::new(1, 1, TransactionType::Resolve(None))
+        );
+        assert!(exchange.handle(&resolve).is_ok());
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.held, Amount::new(0, 0));
+        assert_eq!(client.available, Amount::new(100, 0));
+    }
+
+    #[test]
+    fn test_dispute_chargeback() {
+        let mut exchange = Exchange::new();
+        let deposit = DisputableTransaction::deposit(1, 1, Amount::new(100, 0));
+        assert!(exchange.handle(&deposit.transaction()).is_ok());
+        let dispute = deposit.dispute();
+        assert!(exchange.handle(&dispute.transaction()).is_ok());
+        assert!(exchange.handle(&dispute.chargeback()).is_ok());
+        let client = exchange.get_client(1).unwrap();
+        assert_eq!(client.total, Amount::new(0, 0));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn test_withdraw() {
+        let withdraw = DisputableTransaction::withdraw(2, 1, Amount::new(40, 0));
+        assert_eq!(
+            withdraw.transaction(),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(40, 0)))
+        );
+        assert_eq!(
+            withdraw.dispute().chargeback(),
+            Transaction::new(2, 1, TransactionType::Chargeback)
+        );
+    }
+}
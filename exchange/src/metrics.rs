@@ -0,0 +1,89 @@
+use std::fmt;
+
+use crate::Balance;
+
+/// A point-in-time summary of an exchange for monitoring, see
+/// `Exchange::metrics_snapshot`.
+///
+/// Its `Display` implementation renders the metrics in the Prometheus text
+/// exposition format, so they can be served from a `/metrics` endpoint as-is.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct ExchangeMetrics {
+    /// Number of clients known to the exchange
+    pub clients: usize,
+    /// Number of locked clients
+    pub locked_clients: usize,
+    /// Number of accepted transactions, including disputes, resolves and
+    /// chargebacks
+    pub transactions: usize,
+    /// Number of transactions that are currently disputed
+    pub open_disputes: usize,
+    /// Sum of the total funds of all clients
+    pub total_liabilities: Balance,
+}
+
+impl fmt::Display for ExchangeMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gauges: [(&str, &str, &dyn fmt::Display); 5] = [
+            (
+                "exchange_clients",
+                "Number of clients known to the exchange",
+                &self.clients,
+            ),
+            (
+                "exchange_locked_clients",
+                "Number of locked clients",
+                &self.locked_clients,
+            ),
+            (
+                "exchange_transactions",
+                "Number of accepted transactions",
+                &self.transactions,
+            ),
+            (
+                "exchange_open_disputes",
+                "Number of transactions that are currently disputed",
+                &self.open_disputes,
+            ),
+            (
+                "exchange_total_liabilities",
+                "Sum of the total funds of all clients",
+                &self.total_liabilities,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            writeln!(f, "# HELP {name} {help}")?;
+            writeln!(f, "# TYPE {name} gauge")?;
+            writeln!(f, "{name} {value}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_prometheus() {
+        let metrics = ExchangeMetrics {
+            clients: 2,
+            locked_clients: 1,
+            transactions: 5,
+            open_disputes: 0,
+            total_liabilities: Balance::new(125, 1),
+        };
+        let text = metrics.to_string();
+        assert!(text.contains("# TYPE exchange_clients gauge\nexchange_clients 2\n"));
+        assert!(text.contains("\nexchange_locked_clients 1\n"));
+        assert!(text.contains("\nexchange_transactions 5\n"));
+        assert!(text.contains("\nexchange_open_disputes 0\n"));
+        assert!(text.contains("\nexchange_total_liabilities 12.5\n"));
+        assert_eq!(
+            text.lines()
+                .filter(|line| line.starts_with("# HELP"))
+                .count(),
+            5
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt;
+
+use crate::{Client, ClientID, ExchangeError, Transaction, TransactionID};
+
+/// Callback invoked for every transaction handled by an exchange, e.g. to
+/// collect metrics.
+///
+/// It receives the transaction and the outcome of `Exchange::handle`: the
+/// updated client if the transaction was accepted, or the error it caused.
+pub type Observer<C = ClientID, T = TransactionID> =
+    Box<dyn FnMut(&Transaction<C, T>, Result<&Client<C>, &ExchangeError<C, T>>) + Send>;
+
+/// The observer of an exchange, if any
+pub(crate) struct ObserverSlot<C, T>(Option<Observer<C, T>>);
+
+impl<C, T> ObserverSlot<C, T> {
+    pub(crate) fn set(&mut self, observer: Observer<C, T>) -> Option<Observer<C, T>> {
+        self.0.replace(observer)
+    }
+
+    pub(crate) fn notify(
+        &mut self,
+        transaction: &Transaction<C, T>,
+        outcome: Result<&Client<C>, &ExchangeError<C, T>>,
+    ) {
+        if let Some(observer) = &mut self.0 {
+            observer(transaction, outcome);
+        }
+    }
+}
+
+// Deriving would require `C: Default` and `T: Default`
+impl<C, T> Default for ObserverSlot<C, T> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<C, T> fmt::Debug for ObserverSlot<C, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.0.is_some() { "Some(..)" } else { "None" })
+    }
+}
@@ -1,27 +1,51 @@
-use crate::{Client, ClientID, ExchangeError};
+use crate::{Client, ClientID, ExchangeError, Id};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
 /// Stores information of all clients of the exchange
 /// It handles client lookup and registration
 #[derive(Debug, Clone)]
-pub struct Registry {
+#[cfg_attr(
+    feature = "serde",
+    derive(Serialize, Deserialize),
+    serde(bound(
+        serialize = "C: Id + Serialize",
+        deserialize = "C: Id + Deserialize<'de>"
+    ))
+)]
+pub struct Registry<C = ClientID> {
     /// Map of clients active in the registry
-    pub clients: HashMap<ClientID, Client>,
+    #[cfg_attr(feature = "serde", serde(with = "exact"))]
+    pub clients: HashMap<C, Client<C>>,
 }
 
-impl Registry {
+impl<C: Id> Registry<C> {
     /// Create a new, empty registry of clients
+    #[must_use]
     pub fn new() -> Self {
         let clients = HashMap::new();
         Registry { clients }
     }
 
+    /// Create a new, empty registry with room for at least `capacity` clients
+    /// before it has to reallocate
+    #[must_use]
+    pub fn with_capacity(capacity: usize) -> Self {
+        let clients = HashMap::with_capacity(capacity);
+        Registry { clients }
+    }
+
     /// Get mutable information for client with given id
     /// Note that this will always return a client (and not an option):
     /// If a client doesn't exist, it creates a new record
     /// If a client is locked, an error is returned as the client can no longer be modified.
     /// Use `get` to get a read-only state in this case.
-    pub fn get_mut(&mut self, id: &ClientID) -> Result<&mut Client, ExchangeError> {
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::Locked` if the client is locked
+    pub fn get_mut<T: Id>(&mut self, id: &C) -> Result<&mut Client<C>, ExchangeError<C, T>> {
         let client = self.clients.entry(*id).or_insert(Client::new(*id));
         if client.locked {
             return Err(ExchangeError::Locked(*client));
@@ -29,13 +53,156 @@ impl Registry {
         Ok(client)
     }
 
+    /// Get information for an existing client with given id. Unlike
+    /// `get_mut`, this never creates a client, e.g. for operations that refer
+    /// to earlier transactions of the client.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::UnknownClient` if the client doesn't exist and
+    /// `ExchangeError::Locked` if it is locked
+    pub fn get_existing<T: Id>(&self, id: &C) -> Result<&Client<C>, ExchangeError<C, T>> {
+        match self.clients.get(id) {
+            Some(client) if client.locked => Err(ExchangeError::Locked(*client)),
+            Some(client) => Ok(client),
+            None => Err(ExchangeError::UnknownClient(*id)),
+        }
+    }
+
     /// Get information for client with given id (if existing)
-    pub fn get(&mut self, id: &ClientID) -> Option<&Client> {
-        self.clients.get(&id)
+    #[must_use]
+    pub fn get(&self, id: &C) -> Option<&Client<C>> {
+        self.clients.get(id)
     }
 
     /// Register client
-    pub fn register(&mut self, client: Client) -> Option<Client> {
+    pub fn register(&mut self, client: Client<C>) -> Option<Client<C>> {
         self.clients.insert(client.id, client)
     }
+
+    /// Remove client with given id (if existing)
+    pub fn remove(&mut self, id: &C) -> Option<Client<C>> {
+        self.clients.remove(id)
+    }
+}
+
+impl<C: Id> Default for Registry<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The serialization of `Client` rounds amounts for display purposes.
+/// Snapshots of the registry must be lossless, so clients get serialized with
+/// their exact amounts here.
+#[cfg(feature = "serde")]
+mod exact {
+    use std::collections::HashMap;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::{Amount, Balance, Client, Id};
+
+    #[derive(Serialize, Deserialize)]
+    struct ExactClient<C> {
+        client: C,
+        #[serde(with = "crate::amount::exact")]
+        available: Balance,
+        #[serde(with = "crate::amount::exact")]
+        held: Balance,
+        #[serde(with = "crate::amount::exact")]
+        total: Balance,
+        locked: bool,
+        #[serde(default)]
+        deposit_count: u32,
+        #[serde(default)]
+        withdraw_count: u32,
+        #[serde(default)]
+        deposit_volume: Amount,
+        #[serde(default)]
+        withdraw_volume: Amount,
+    }
+
+    pub(super) fn serialize<C, S>(
+        clients: &HashMap<C, Client<C>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        C: Id + Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(clients.values().map(|client| ExactClient {
+            client: client.id,
+            available: client.available,
+            held: client.held,
+            total: client.total,
+            locked: client.locked,
+            deposit_count: client.deposit_count,
+            withdraw_count: client.withdraw_count,
+            deposit_volume: client.deposit_volume,
+            withdraw_volume: client.withdraw_volume,
+        }))
+    }
+
+    pub(super) fn deserialize<'de, C, D>(deserializer: D) -> Result<HashMap<C, Client<C>>, D::Error>
+    where
+        C: Id + Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        let clients = Vec::<ExactClient<C>>::deserialize(deserializer)?;
+        Ok(clients
+            .into_iter()
+            .map(|client| {
+                let ExactClient {
+                    client: id,
+                    available,
+                    held,
+                    total,
+                    locked,
+                    deposit_count,
+                    withdraw_count,
+                    deposit_volume,
+                    withdraw_volume,
+                } = client;
+                (
+                    id,
+                    Client {
+                        id,
+                        available,
+                        held,
+                        total,
+                        locked,
+                        deposit_count,
+                        withdraw_count,
+                        deposit_volume,
+                        withdraw_volume,
+                    },
+                )
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TransactionID;
+
+    #[test]
+    fn test_get_existing() {
+        let mut registry = Registry::new();
+        assert_eq!(
+            registry.get_existing::<TransactionID>(&1),
+            Err(ExchangeError::UnknownClient(1))
+        );
+        assert!(registry.get(&1).is_none());
+        assert!(registry.get_mut::<TransactionID>(&1).is_ok());
+        assert!(registry.get_existing::<TransactionID>(&1).is_ok());
+
+        registry.get_mut::<TransactionID>(&1).unwrap().locked = true;
+        assert!(matches!(
+            registry.get_existing::<TransactionID>(&1),
+            Err(ExchangeError::Locked(_))
+        ));
+    }
 }
@@ -0,0 +1,44 @@
+use crate::{ClientID, ErrorKind, ExchangeError, Id, Transaction, TransactionID};
+
+/// A transaction that was rejected by the exchange, together with the rule that
+/// rejected it
+#[derive(Debug, Clone, PartialEq)]
+pub struct RejectionRecord<C = ClientID, T = TransactionID> {
+    /// The rejected transaction
+    pub transaction: Transaction<C, T>,
+    /// The rule that rejected the transaction
+    pub reason: ErrorKind,
+    /// Human-readable description of the rejection
+    pub message: String,
+}
+
+impl<C: Id, T: Id> RejectionRecord<C, T> {
+    /// Create a new rejection record for a transaction from the error it caused
+    #[must_use]
+    pub fn new(transaction: Transaction<C, T>, err: &ExchangeError<C, T>) -> Self {
+        Self {
+            transaction,
+            reason: err.kind(),
+            message: err.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rejection_from_error() {
+        let transaction = Transaction::new(1, 1, crate::TransactionType::Dispute(None));
+        let err = ExchangeError::InvalidTransaction(
+            transaction,
+            ErrorKind::UnknownTransaction,
+            "The given transaction ID DOES NOT exist".to_string(),
+        );
+        let rejection = RejectionRecord::new(transaction, &err);
+        assert_eq!(rejection.transaction, transaction);
+        assert_eq!(rejection.reason, ErrorKind::UnknownTransaction);
+        assert!(rejection.message.contains("DOES NOT exist"));
+    }
+}
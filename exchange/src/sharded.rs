@@ -0,0 +1,139 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use crate::{Client, ErrorKind, Exchange, ExchangeError, Transaction, TransactionType};
+
+/// An exchange split into independent shards by client ID, so that
+/// transactions of different clients can be handled in parallel.
+///
+/// Every shard is a separate `Exchange` behind a mutex. Transactions are routed
+/// to the shard of their client (see `Exchange::shard`), so the transactions of
+/// a single client are handled in the order they are submitted. There is no
+/// global order across shards though: transactions of clients on different
+/// shards may be handled in any order relative to each other.
+///
+/// As shards don't know about each other, transaction IDs are only checked for
+/// uniqueness within a shard, and transfers between clients on different shards
+/// are rejected.
+#[derive(Debug)]
+pub struct ShardedExchange {
+    shards: Vec<Mutex<Exchange>>,
+}
+
+impl ShardedExchange {
+    /// Create a new, empty exchange with the given number of shards
+    ///
+    /// ## Panics
+    ///
+    /// Panics if `shards` is zero
+    #[must_use]
+    pub fn new(shards: usize) -> Self {
+        assert!(shards > 0, "A sharded exchange needs at least one shard");
+        Self {
+            shards: (0..shards).map(|_| Mutex::new(Exchange::new())).collect(),
+        }
+    }
+
+    /// Handle a transaction on the shard of its client, see `Exchange::handle`
+    ///
+    /// ## Errors
+    ///
+    /// Returns error in case of an invalid transaction, or if a transfer
+    /// crosses shards
+    pub fn handle(&self, transaction: &Transaction) -> Result<Client, ExchangeError> {
+        let shard = Exchange::shard(transaction.client, self.shards.len());
+        if let TransactionType::Transfer { to, .. } = transaction.ttype {
+            if Exchange::shard(to, self.shards.len()) != shard {
+                return Err(ExchangeError::InvalidTransaction(
+                    *transaction,
+                    ErrorKind::CrossShardTransfer,
+                    "Source and destination of a transfer are on different shards".to_string(),
+                ));
+            }
+        }
+        self.lock(shard).handle(transaction)
+    }
+
+    /// Returns an iterator over all clients of all shards. Every shard is
+    /// locked only while its clients are collected.
+    pub fn clients(&self) -> impl Iterator<Item = Client> + '_ {
+        (0..self.shards.len())
+            .flat_map(move |shard| self.lock(shard).clients().copied().collect::<Vec<_>>())
+    }
+
+    fn lock(&self, shard: usize) -> MutexGuard<'_, Exchange> {
+        // Transactions are staged before they are committed, so a shard is
+        // consistent even if a thread panicked while holding the lock
+        self.shards[shard]
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::{Amount, ClientID};
+
+    fn transactions() -> Vec<Transaction> {
+        let mut transactions = Vec::new();
+        for tx in 0..200 {
+            let client = (tx % 13) as ClientID;
+            let ttype = if tx % 3 == 2 {
+                TransactionType::Withdraw(Amount::new(1, 0))
+            } else {
+                TransactionType::Deposit(Amount::new(i64::from(tx), 1))
+            };
+            transactions.push(Transaction::new(tx, client, ttype));
+        }
+        transactions.push(Transaction::new(4, 4, TransactionType::Dispute(None)));
+        transactions.push(Transaction::new(20, 7, TransactionType::Dispute(None)));
+        transactions.push(Transaction::new(20, 7, TransactionType::Chargeback));
+        transactions
+    }
+
+    #[test]
+    fn test_sharded_matches_single() {
+        let mut single = Exchange::new();
+        single.handle_all(transactions());
+
+        let shards = 4;
+        let sharded = ShardedExchange::new(shards);
+        thread::scope(|scope| {
+            for shard in 0..shards {
+                let sharded = &sharded;
+                scope.spawn(move || {
+                    for transaction in transactions() {
+                        if Exchange::shard(transaction.client, shards) == shard {
+                            let _ = sharded.handle(&transaction);
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut expected: Vec<Client> = single.clients().copied().collect();
+        expected.sort_by_key(|client| client.id);
+        let mut actual: Vec<Client> = sharded.clients().collect();
+        actual.sort_by_key(|client| client.id);
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_cross_shard_transfer() {
+        let sharded = ShardedExchange::new(2);
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0)));
+        assert!(sharded.handle(&tx).is_ok());
+
+        let transfer = |tx, to| {
+            let amount = Amount::new(1, 0);
+            Transaction::new(tx, 1, TransactionType::Transfer { to, amount })
+        };
+        assert_eq!(
+            sharded.handle(&transfer(2, 2)).unwrap_err().kind(),
+            ErrorKind::CrossShardTransfer
+        );
+        assert!(sharded.handle(&transfer(3, 3)).is_ok());
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::BuildHasher,
+};
+
+use crate::{ClientID, Id, Transaction, TransactionID};
+
+/// Datastore for the transactions accepted by the exchange.
+///
+/// The exchange only needs to store transactions and look them up by ID, so
+/// any datastore providing these operations can be plugged in, e.g. one backed
+/// by an external database. Transactions are returned by value, as a store
+/// might not hold them in memory.
+pub trait TransactionStore<C = ClientID, T = TransactionID> {
+    /// Store a transaction under its ID, replacing any previous transaction
+    /// with the same ID
+    fn insert(&mut self, transaction: Transaction<C, T>);
+
+    /// Look up the transaction with the given ID
+    fn get(&self, id: T) -> Option<Transaction<C, T>>;
+
+    /// Check whether a transaction with the given ID is stored
+    fn contains(&self, id: T) -> bool;
+
+    /// Remove the transaction with the given ID, returning it if it was stored
+    fn remove(&mut self, id: T) -> Option<Transaction<C, T>>;
+
+    /// Number of stored transactions
+    fn len(&self) -> usize;
+
+    /// Check whether no transactions are stored
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// In-memory store without ordering
+impl<C: Id, T: Id, H: BuildHasher> TransactionStore<C, T> for HashMap<T, Transaction<C, T>, H> {
+    fn insert(&mut self, transaction: Transaction<C, T>) {
+        HashMap::insert(self, transaction.tx, transaction);
+    }
+
+    fn get(&self, id: T) -> Option<Transaction<C, T>> {
+        HashMap::get(self, &id).copied()
+    }
+
+    fn contains(&self, id: T) -> bool {
+        self.contains_key(&id)
+    }
+
+    fn remove(&mut self, id: T) -> Option<Transaction<C, T>> {
+        HashMap::remove(self, &id)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+}
+
+/// The default in-memory store, ordered by transaction ID
+impl<C: Id, T: Id> TransactionStore<C, T> for BTreeMap<T, Transaction<C, T>> {
+    fn insert(&mut self, transaction: Transaction<C, T>) {
+        BTreeMap::insert(self, transaction.tx, transaction);
+    }
+
+    fn get(&self, id: T) -> Option<Transaction<C, T>> {
+        BTreeMap::get(self, &id).copied()
+    }
+
+    fn contains(&self, id: T) -> bool {
+        self.contains_key(&id)
+    }
+
+    fn remove(&mut self, id: T) -> Option<Transaction<C, T>> {
+        BTreeMap::remove(self, &id)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Amount, TransactionType};
+
+    fn check_store<S: TransactionStore>(mut store: S) {
+        let transaction = Transaction::new(1, 2, TransactionType::Deposit(Amount::new(5, 0)));
+        assert!(!store.contains(1));
+        store.insert(transaction);
+        assert!(store.contains(1));
+        assert_eq!(store.get(1), Some(transaction));
+        assert_eq!(store.get(2), None);
+        assert_eq!(store.remove(1), Some(transaction));
+        assert!(!store.contains(1));
+    }
+
+    #[test]
+    fn test_hashmap_store() {
+        check_store(HashMap::<TransactionID, Transaction>::new());
+    }
+
+    #[test]
+    fn test_btreemap_store() {
+        check_store(BTreeMap::<TransactionID, Transaction>::new());
+    }
+}
@@ -0,0 +1,67 @@
+use futures_util::stream::{Stream, StreamExt};
+
+use crate::{Client, Exchange, ExchangeError, Id, Transaction, TransactionStore};
+
+impl<S: TransactionStore<C, T>, C: Id, T: Id> Exchange<S, C, T> {
+    /// Handle the transactions of a stream, e.g. read from a network source,
+    /// as they arrive. Returns a stream of the outcome of `Exchange::handle`
+    /// for every transaction, in order.
+    ///
+    /// The returned stream only pulls the next transaction when it is polled
+    /// itself, so a slow consumer applies backpressure to the source instead
+    /// of transactions getting buffered. The exchange stays borrowed until
+    /// the returned stream is dropped.
+    pub fn handle_stream<'a, St>(
+        &'a mut self,
+        transactions: St,
+    ) -> impl Stream<Item = Result<Client<C>, ExchangeError<C, T>>> + 'a
+    where
+        St: Stream<Item = Transaction<C, T>> + 'a,
+    {
+        transactions.map(move |transaction| self.handle(&transaction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{stream, FutureExt};
+
+    use super::*;
+    use crate::{Amount, Balance, ErrorKind, TransactionType};
+
+    #[test]
+    fn test_handle_stream() {
+        let transactions = stream::iter(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(10, 0))),
+            Transaction::new(2, 1, TransactionType::Withdraw(Amount::new(20, 0))),
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(4, 0))),
+        ]);
+        let mut exchange = Exchange::new();
+        let outcomes = exchange
+            .handle_stream(transactions)
+            .collect::<Vec<_>>()
+            .now_or_never()
+            .unwrap();
+        assert_eq!(outcomes.len(), 3);
+        assert_eq!(outcomes[0].as_ref().unwrap().total, Balance::new(10, 0));
+        assert_eq!(
+            outcomes[1].as_ref().unwrap_err().kind(),
+            ErrorKind::InsufficientFunds
+        );
+        assert_eq!(outcomes[2].as_ref().unwrap().total, Balance::new(6, 0));
+        assert_eq!(exchange.get_client(1).unwrap().total, Balance::new(6, 0));
+    }
+
+    #[test]
+    fn test_handle_stream_lazily() {
+        let transactions = stream::iter(
+            (1..=3).map(|tx| Transaction::new(tx, 1, TransactionType::Deposit(Amount::new(1, 0)))),
+        );
+        let mut exchange = Exchange::new();
+        let mut outcomes = exchange.handle_stream(transactions);
+        assert!(outcomes.next().now_or_never().unwrap().is_some());
+        drop(outcomes);
+        // Only the polled transaction was handled
+        assert_eq!(exchange.get_client(1).unwrap().total, Balance::new(1, 0));
+    }
+}
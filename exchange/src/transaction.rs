@@ -1,56 +1,315 @@
-use crate::{Amount, ClientID};
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+use chrono::{DateTime, Utc};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{Amount, ClientID, ExchangeError, Id};
 
 /// ID of a single transaction. It is unique across the entire exchange.
 /// Make transaction ID a separate type to allow for future upgrades
 pub type TransactionID = u32;
 
 /// Types of transactions accepted on the exchange
-#[derive(Debug, Copy, Clone, PartialEq)]
-pub enum TransactionType {
+///
+/// Amounts compare by their decimal value, so `1.0` equals `1.00`, and equal
+/// amounts hash the same regardless of their scale.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TransactionType<C = ClientID> {
     /// A deposit is a credit to the client's asset account, meaning it should
     /// increase the available and total funds of the client account
     Deposit(Amount),
     /// A withdraw is a debit to the client's asset account, meaning it should decrease the available and total funds of the client account
     Withdraw(Amount),
+    /// A transfer moves funds from the client's asset account to the account
+    /// of another client. It decreases the available and total funds of the
+    /// client and increases those of the destination client by the same amount.
+    Transfer {
+        /// The client receiving the funds
+        to: C,
+        /// The amount to transfer
+        amount: Amount,
+    },
     /// A dispute represents a client's claim that a transaction was erroneous
     /// and should be reversed.
-    Dispute,
+    /// A dispute may cover only part of the amount of the transaction; without
+    /// an amount, the full amount is disputed.
+    Dispute(Option<Amount>),
     /// A resolve represents a resolution to a dispute, releasing the associated
     /// held funds. Funds that were previously disputed are no longer disputed.
     /// This means that the clients held funds should decrease by the amount no
     /// longer disputed, their available funds should increase by the amount no
     /// longer disputed, and their total funds should remain the same.
-    Resolve,
+    /// A resolve may release only part of the disputed amount; the dispute
+    /// stays open until all of it was released.
+    Resolve(Option<Amount>),
     /// A chargeback is the final state of a dispute and represents the client
     /// reversing a transaction. Funds that were held have now been withdrawn.
     /// This means that the clients held funds and total funds should decrease
     /// by the amount previously disputed. If a chargeback occurs the client's
     /// account should be immediately frozen.
     Chargeback,
+    /// A hold is placed by an operator, e.g. for a pending compliance review.
+    /// It moves funds from the available to the held funds of the client,
+    /// without referring to another transaction.
+    Hold(Amount),
+    /// A release lifts an administrative hold, moving funds from the held back
+    /// to the available funds of the client. Releasing more than the funds
+    /// held by operators is rejected, so funds held for disputes stay held.
+    Release(Amount),
+    /// A transaction of a type not known to the exchange, e.g. a fee or a
+    /// rebate, with an optional amount. It gets applied by the handler
+    /// registered for its type (see `Exchange::register_handler`).
+    Custom(CustomType, Option<Amount>),
+}
+
+impl<C: Id> TransactionType<C> {
+    /// Create a transaction type of the given kind, e.g. after parsing the kind
+    /// and the amount from separate CSV columns. `amount` is required for
+    /// deposits, withdrawals, transfers, holds and releases, optional for
+    /// disputes and resolves
+    /// and must be absent otherwise. `to` is required for transfers only.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::MalformedTransaction` naming the mismatch if
+    /// `amount` or `to` don't match the kind
+    pub fn from_kind(
+        kind: TransactionKind,
+        amount: Option<Amount>,
+        to: Option<C>,
+    ) -> Result<Self, ExchangeError<C>> {
+        let ttype = match (kind, amount, to) {
+            (TransactionKind::Deposit, Some(amount), None) => TransactionType::Deposit(amount),
+            (TransactionKind::Withdraw, Some(amount), None) => TransactionType::Withdraw(amount),
+            (TransactionKind::Transfer, Some(amount), Some(to)) => {
+                TransactionType::Transfer { to, amount }
+            }
+            (TransactionKind::Dispute, amount, None) => TransactionType::Dispute(amount),
+            (TransactionKind::Resolve, amount, None) => TransactionType::Resolve(amount),
+            (TransactionKind::Chargeback, None, None) => TransactionType::Chargeback,
+            (TransactionKind::Hold, Some(amount), None) => TransactionType::Hold(amount),
+            (TransactionKind::Release, Some(amount), None) => TransactionType::Release(amount),
+            _ => return Err(mismatch(kind, amount.is_some(), to.is_some())),
+        };
+        Ok(ttype)
+    }
+
+    /// The kind of the transaction, or `None` for custom transactions
+    #[must_use]
+    pub const fn kind(&self) -> Option<TransactionKind> {
+        let kind = match self {
+            TransactionType::Deposit(_) => TransactionKind::Deposit,
+            TransactionType::Withdraw(_) => TransactionKind::Withdraw,
+            TransactionType::Transfer { .. } => TransactionKind::Transfer,
+            TransactionType::Dispute(_) => TransactionKind::Dispute,
+            TransactionType::Resolve(_) => TransactionKind::Resolve,
+            TransactionType::Chargeback => TransactionKind::Chargeback,
+            TransactionType::Hold(_) => TransactionKind::Hold,
+            TransactionType::Release(_) => TransactionKind::Release,
+            TransactionType::Custom(..) => return None,
+        };
+        Some(kind)
+    }
+}
+
+/// Describe why the amount or destination of a transaction doesn't match its
+/// kind, see `TransactionType::from_kind`
+fn mismatch<C>(kind: TransactionKind, amount: bool, to: bool) -> ExchangeError<C> {
+    let reason = match (kind, amount, to) {
+        (TransactionKind::Transfer, false, _) => "requires an amount",
+        (TransactionKind::Transfer, _, false) => "requires a destination",
+        (_, _, true) => "must not carry a destination",
+        (TransactionKind::Chargeback, true, _) => "must not carry an amount",
+        _ => "requires an amount",
+    };
+    ExchangeError::MalformedTransaction(format!("{kind} {reason}"))
+}
+
+/// Displays the name of the type as used in input files, e.g. `deposit`.
+/// Amounts are not part of the name.
+impl<C: Id> fmt::Display for TransactionType<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self, self.kind()) {
+            (TransactionType::Custom(name, _), _) => name.fmt(f),
+            (_, kind) => kind.map_or(Ok(()), |kind| kind.fmt(f)),
+        }
+    }
+}
+
+/// The kind of a transaction type without its amount, as named in input files
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum TransactionKind {
+    /// See `TransactionType::Deposit`
+    Deposit,
+    /// See `TransactionType::Withdraw`
+    Withdraw,
+    /// See `TransactionType::Transfer`
+    Transfer,
+    /// See `TransactionType::Dispute`
+    Dispute,
+    /// See `TransactionType::Resolve`
+    Resolve,
+    /// See `TransactionType::Chargeback`
+    Chargeback,
+    /// See `TransactionType::Hold`
+    Hold,
+    /// See `TransactionType::Release`
+    Release,
+}
+
+impl FromStr for TransactionKind {
+    type Err = ExchangeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kind = match s {
+            "deposit" => TransactionKind::Deposit,
+            // The docs mention "withdraw" and "withdrawal", so let's accept both
+            "withdraw" | "withdrawal" => TransactionKind::Withdraw,
+            "transfer" => TransactionKind::Transfer,
+            "dispute" => TransactionKind::Dispute,
+            "resolve" => TransactionKind::Resolve,
+            "chargeback" => TransactionKind::Chargeback,
+            "hold" => TransactionKind::Hold,
+            "release" => TransactionKind::Release,
+            _ => return Err(ExchangeError::UnsupportedTransaction(format!("type {s}"))),
+        };
+        Ok(kind)
+    }
+}
+
+impl fmt::Display for TransactionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TransactionKind::Deposit => "deposit",
+            TransactionKind::Withdraw => "withdrawal",
+            TransactionKind::Transfer => "transfer",
+            TransactionKind::Dispute => "dispute",
+            TransactionKind::Resolve => "resolve",
+            TransactionKind::Chargeback => "chargeback",
+            TransactionKind::Hold => "hold",
+            TransactionKind::Release => "release",
+        })
+    }
+}
+
+/// Name of a custom transaction type, e.g. `fee` or `rebate`.
+///
+/// The name is stored inline, so that transactions can still be copied
+/// cheaply. Names must not be empty and can be up to `CustomType::MAX_LEN`
+/// bytes long.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CustomType {
+    len: u8,
+    name: [u8; CustomType::MAX_LEN],
+}
+
+impl CustomType {
+    /// Maximum length of a name in bytes
+    pub const MAX_LEN: usize = 16;
+
+    /// The name of the type
+    #[must_use]
+    pub fn as_str(&self) -> &str {
+        // The bytes were copied from a `str`, so they are always valid UTF-8
+        std::str::from_utf8(&self.name[..usize::from(self.len)]).unwrap_or_default()
+    }
+}
+
+impl FromStr for CustomType {
+    type Err = ExchangeError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match u8::try_from(name.len()) {
+            Ok(len) if len > 0 && name.len() <= Self::MAX_LEN => {
+                let mut custom = Self {
+                    len,
+                    name: [0; Self::MAX_LEN],
+                };
+                custom.name[..name.len()].copy_from_slice(name.as_bytes());
+                Ok(custom)
+            }
+            _ => Err(ExchangeError::InvalidTypeName(name.to_string())),
+        }
+    }
+}
+
+impl fmt::Debug for CustomType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for CustomType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for CustomType {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for CustomType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
 }
 
 /// Transactions contain all necessary information of a single transaction on
 /// the exchange
-#[derive(Debug, Copy, Clone, PartialEq)]
-pub struct Transaction {
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Transaction<C = ClientID, T = TransactionID> {
     /// ID of transaction
-    pub tx: TransactionID,
+    pub tx: T,
     /// Client ID for transaction
-    pub client: ClientID,
+    pub client: C,
     /// Transaction type (with optional amount)
-    pub ttype: TransactionType,
+    pub ttype: TransactionType<C>,
+    /// When the transaction happened, if known. The exchange handles
+    /// transactions in the order they are submitted, regardless of their
+    /// timestamps.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub timestamp: Option<DateTime<Utc>>,
 }
 
-impl Transaction {
+impl<C: Id, T: Id> Transaction<C, T> {
     /// Create a new transaction
-    pub fn new(tx: TransactionID, client: ClientID, ttype: TransactionType) -> Self {
-        Self { tx, client, ttype }
+    #[must_use]
+    pub const fn new(tx: T, client: C, ttype: TransactionType<C>) -> Self {
+        Self {
+            tx,
+            client,
+            ttype,
+            timestamp: None,
+        }
+    }
+
+    /// Set the time the transaction happened
+    #[must_use]
+    pub const fn with_timestamp(mut self, timestamp: DateTime<Utc>) -> Self {
+        self.timestamp = Some(timestamp);
+        self
     }
 
-    /// Return the amount of the transaction (if any)
-    pub fn amount(&self) -> Option<Amount> {
+    /// Return the amount moved by a deposit, withdrawal, transfer, hold or
+    /// release (if any)
+    #[must_use]
+    pub const fn amount(&self) -> Option<Amount> {
         match self.ttype {
-            TransactionType::Deposit(amount) | TransactionType::Withdraw(amount) => Some(amount),
+            TransactionType::Deposit(amount)
+            | TransactionType::Withdraw(amount)
+            | TransactionType::Transfer { amount, .. }
+            | TransactionType::Hold(amount)
+            | TransactionType::Release(amount) => Some(amount),
             _ => None,
         }
     }
@@ -60,15 +319,109 @@ impl Transaction {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_transaction_type_round_trip() {
+        let types = [
+            TransactionType::Deposit(Amount::new(15, 1)),
+            TransactionType::Withdraw(Amount::new(15, 1)),
+            TransactionType::Transfer {
+                to: 2,
+                amount: Amount::new(15, 1),
+            },
+            TransactionType::Dispute(None),
+            TransactionType::Dispute(Some(Amount::new(5, 1))),
+            TransactionType::Resolve(None),
+            TransactionType::Resolve(Some(Amount::new(5, 1))),
+            TransactionType::Chargeback,
+            TransactionType::Hold(Amount::new(15, 1)),
+            TransactionType::Release(Amount::new(15, 1)),
+        ];
+        for ttype in types {
+            let kind: TransactionKind = ttype.to_string().parse().unwrap();
+            assert_eq!(Some(kind), ttype.kind());
+            let to = match ttype {
+                TransactionType::Transfer { to, .. } => Some(to),
+                _ => None,
+            };
+            let amount = match ttype {
+                TransactionType::Dispute(amount) | TransactionType::Resolve(amount) => amount,
+                _ => Transaction::<_, TransactionID>::new(1, 1, ttype).amount(),
+            };
+            assert_eq!(TransactionType::from_kind(kind, amount, to), Ok(ttype));
+        }
+    }
+
+    #[test]
+    fn test_transaction_kind_parse() {
+        assert_eq!("withdraw".parse(), Ok(TransactionKind::Withdraw));
+        assert_eq!("withdrawal".parse(), Ok(TransactionKind::Withdraw));
+        assert_eq!(TransactionKind::Withdraw.to_string(), "withdrawal");
+        assert!(matches!(
+            "fee".parse::<TransactionKind>(),
+            Err(ExchangeError::UnsupportedTransaction(_))
+        ));
+        let name = "fee".parse().unwrap();
+        let ttype: TransactionType = TransactionType::Custom(name, None);
+        assert_eq!(ttype.to_string(), "fee");
+        assert_eq!(ttype.kind(), None);
+        assert!(
+            TransactionType::<ClientID>::from_kind(TransactionKind::Deposit, None, None).is_err()
+        );
+        assert!(TransactionType::<ClientID>::from_kind(
+            TransactionKind::Chargeback,
+            Some(Amount::ZERO),
+            None
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_create_transaction() {
         let transaction = Transaction {
             tx: 1,
             client: 2,
             ttype: TransactionType::Deposit(Amount::new(100, 0)),
+            timestamp: None,
         };
         assert_eq!(transaction.tx, 1);
         assert_eq!(transaction.client, 2);
         assert!(matches!(transaction.ttype, TransactionType::Deposit(_)));
     }
+
+    #[test]
+    fn test_custom_type() {
+        let rebate: CustomType = "rebate".parse().unwrap();
+        assert_eq!(rebate.as_str(), "rebate");
+        assert_eq!(rebate.to_string(), "rebate");
+        assert_eq!(rebate, "rebate".parse().unwrap());
+        assert_ne!(rebate, "fee".parse().unwrap());
+    }
+
+    #[test]
+    fn test_custom_type_invalid_name() {
+        assert!("".parse::<CustomType>().is_err());
+        assert!("a-very-long-type-name".parse::<CustomType>().is_err());
+        assert!("sixteen-bytes-ok".parse::<CustomType>().is_ok());
+    }
+
+    #[test]
+    fn test_equal_amounts_of_different_scale() {
+        use std::collections::HashSet;
+
+        let transaction = |amount| Transaction::new(1, 1, TransactionType::Deposit(amount));
+        let one: Amount = "1.0".parse().unwrap();
+        let also_one: Amount = "1.0000".parse().unwrap();
+        assert_ne!(one.value().scale(), also_one.value().scale());
+        assert_eq!(transaction(one), transaction(also_one));
+
+        let transactions: HashSet<Transaction> = vec![
+            transaction(one),
+            transaction(also_one),
+            transaction(Amount::new(1, 0)),
+            transaction(Amount::new(2, 0)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(transactions.len(), 2);
+    }
 }
@@ -0,0 +1,242 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{Adjustment, Exchange, ExchangeError, Id, Transaction, TransactionStore};
+
+/// A line of the write-ahead log. Transactions are written as they are, so
+/// that logs written before adjustments and voids were logged stay readable.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum WalEntry<C, T> {
+    /// A transaction accepted by `Exchange::handle`
+    Transaction(Transaction<C, T>),
+    /// See `Exchange::apply_adjustment`
+    Adjustment { adjustment: Adjustment<C> },
+    /// See `Exchange::void_transaction`
+    Void { void: T },
+}
+
+/// Serializes an entry and appends it to the log
+type Append<C, T> = Box<dyn FnMut(&WalEntry<C, T>) -> io::Result<()> + Send>;
+
+/// Write-ahead log of the changes made to an exchange
+pub(crate) struct Wal<C, T>(Append<C, T>);
+
+impl<C, T> Wal<C, T> {
+    /// Append an entry to the log, flushing it right away so that it survives
+    /// a crash
+    pub(crate) fn append(&mut self, entry: &WalEntry<C, T>) -> io::Result<()> {
+        (self.0)(entry)
+    }
+}
+
+impl<C, T> fmt::Debug for Wal<C, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Wal")
+    }
+}
+
+impl Exchange {
+    /// Reconstruct an exchange from a write-ahead log, see `Exchange::set_wal`
+    ///
+    /// ## Errors
+    ///
+    /// See `Exchange::replay_into`
+    pub fn replay<R: io::Read>(reader: R) -> Result<Exchange, ExchangeError> {
+        let mut exchange = Exchange::new();
+        exchange.replay_into(reader)?;
+        Ok(exchange)
+    }
+}
+
+impl<S, C, T> Exchange<S, C, T>
+where
+    S: TransactionStore<C, T>,
+    C: Id + Serialize + DeserializeOwned + 'static,
+    T: Id + Serialize + DeserializeOwned + 'static,
+{
+    /// Append every transaction accepted by `handle` to a write-ahead log,
+    /// replacing any previous log. Every transaction is written as a line of
+    /// JSON before it gets applied, so the state of the exchange can be
+    /// reconstructed after a crash with `Exchange::replay`. Adjustments and
+    /// voided transactions are logged as well, as `{"adjustment": ...}` and
+    /// `{"void": tx}` lines. Locks, unlocks and blocks are not logged.
+    pub fn set_wal<W: io::Write + Send + 'static>(&mut self, mut writer: W) {
+        self.wal = Some(Wal(Box::new(move |entry| {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+            writer.flush()
+        })));
+    }
+
+    /// Re-apply the transactions, adjustments and voids of a write-ahead log,
+    /// e.g. to an exchange with the same configuration and custom transaction
+    /// handlers as the one that wrote the log. The replayed entries are not
+    /// logged again.
+    ///
+    /// ## Errors
+    ///
+    /// Returns `ExchangeError::Io` if the log cannot be read or is malformed,
+    /// or the error of the first logged entry that gets rejected. The entries
+    /// before it stay applied.
+    pub fn replay_into<R: io::Read>(&mut self, reader: R) -> Result<(), ExchangeError<C, T>> {
+        let wal = self.wal.take();
+        let replayed = self.replay_entries(reader);
+        self.wal = wal;
+        replayed
+    }
+
+    fn replay_entries<R: io::Read>(&mut self, reader: R) -> Result<(), ExchangeError<C, T>> {
+        let io_error = |err: io::Error| ExchangeError::Io(err.to_string());
+        for line in BufReader::new(reader).lines() {
+            let line = line.map_err(io_error)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line)
+                .map_err(|err| ExchangeError::Io(format!("Malformed log entry `{line}`: {err}")))?;
+            match entry {
+                WalEntry::Transaction(transaction) => {
+                    let changes = self.stage(&transaction)?;
+                    self.commit(transaction, changes);
+                }
+                WalEntry::Adjustment { adjustment } => {
+                    self.apply_adjustment(adjustment.client, adjustment.delta, adjustment.note)?;
+                }
+                WalEntry::Void { void } => self.void_transaction(void)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{Amount, Balance, Client, TransactionType};
+
+    /// In-memory log, which can still be read after handing it to an exchange
+    #[derive(Clone, Default)]
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn clients(exchange: &Exchange) -> Vec<Client> {
+        let mut clients: Vec<Client> = exchange.clients().copied().collect();
+        clients.sort_by_key(|client| client.id);
+        clients
+    }
+
+    #[test]
+    fn test_replay() {
+        let log = SharedLog::default();
+        let mut exchange = Exchange::new();
+        exchange.set_wal(log.clone());
+        let rejected = exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 2, TransactionType::Deposit(Amount::new(12_345, 4))),
+            Transaction::new(3, 1, TransactionType::Withdraw(Amount::new(500, 0))),
+            Transaction::new(4, 1, TransactionType::Withdraw(Amount::new(25, 0))),
+            Transaction::new(
+                5,
+                2,
+                TransactionType::Transfer {
+                    to: 3,
+                    amount: Amount::new(1, 0),
+                },
+            ),
+            Transaction::new(1, 1, TransactionType::Dispute(None)),
+            Transaction::new(1, 1, TransactionType::Resolve(Some(Amount::new(20, 0)))),
+        ]);
+        assert_eq!(rejected.len(), 1);
+
+        let log = log.0.lock().unwrap().clone();
+        // Only accepted transactions are logged
+        assert_eq!(std::str::from_utf8(&log).unwrap().lines().count(), 6);
+
+        let mut replayed = Exchange::replay(log.as_slice()).unwrap();
+        assert_eq!(clients(&replayed), clients(&exchange));
+        // Open disputes are restored as well
+        let tx = Transaction::new(1, 1, TransactionType::Chargeback);
+        assert_eq!(replayed.handle(&tx), exchange.handle(&tx));
+    }
+
+    #[test]
+    fn test_replay_adjustments_and_voids() {
+        let log = SharedLog::default();
+        let mut exchange = Exchange::new();
+        exchange.set_wal(log.clone());
+        exchange.apply(vec![
+            Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0))),
+            Transaction::new(2, 1, TransactionType::Deposit(Amount::new(20, 0))),
+        ]);
+        exchange
+            .apply_adjustment(1, Balance::new(-5, 0), "Fee correction".to_string())
+            .unwrap();
+        exchange.void_transaction(2).unwrap();
+        // Rejected changes are not logged
+        assert!(exchange.void_transaction(3).is_err());
+
+        let log = log.0.lock().unwrap().clone();
+        let lines: Vec<&str> = std::str::from_utf8(&log).unwrap().lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[3], "{\"void\":2}");
+
+        let replayed = Exchange::replay(log.as_slice()).unwrap();
+        assert_eq!(clients(&replayed), clients(&exchange));
+        assert_eq!(replayed.get_client(1).unwrap().total, Amount::new(95, 0));
+        assert!(replayed.adjustments().eq(exchange.adjustments()));
+    }
+
+    #[test]
+    fn test_replay_malformed() {
+        let log = b"{\"tx\":1,\"client\":1,\"ttype\":{\"Deposit\":\"5\"}}\nnot json\n";
+        assert!(matches!(
+            Exchange::replay(&log[..]),
+            Err(ExchangeError::Io(_))
+        ));
+
+        // Logged transactions must still be valid
+        let log = b"{\"tx\":1,\"client\":1,\"ttype\":{\"Withdraw\":\"5\"}}\n";
+        assert_eq!(
+            Exchange::replay(&log[..]).unwrap_err().kind(),
+            crate::ErrorKind::InsufficientFunds
+        );
+    }
+
+    #[test]
+    fn test_wal_write_error() {
+        struct Failing;
+
+        impl io::Write for Failing {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::other("disk full"))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut exchange = Exchange::new();
+        exchange.set_wal(Failing);
+        let tx = Transaction::new(1, 1, TransactionType::Deposit(Amount::new(100, 0)));
+        assert!(matches!(exchange.handle(&tx), Err(ExchangeError::Io(_))));
+        // Transactions that could not be logged are not applied
+        assert_eq!(exchange.get_client(1).unwrap().total, Amount::ZERO);
+    }
+}